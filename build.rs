@@ -1,13 +1,21 @@
 use anyhow::*;
 use glob::glob;
+use std::cell::RefCell;
+use std::fmt::Write as _;
 use std::fs::{read_to_string, write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+// Searched for #include after the directory of the including file
+const INCLUDE_DIR: &str = "./src/shaders/include";
+const PERMUTATIONS: &str = "./src/shaders/permutations.ron";
 
 struct ShaderData {
     src: String,
     src_path: PathBuf,
     spv_path: PathBuf,
     kind: shaderc::ShaderKind,
+    defines: Vec<(String, Option<String>)>,
 }
 
 impl ShaderData {
@@ -32,8 +40,143 @@ impl ShaderData {
             src_path,
             spv_path,
             kind,
+            defines: Vec::new(),
         })
     }
+
+    // shader.frag with permutation "alpha_test" becomes shader.alpha_test.frag.spv
+    pub fn permutation(mut self, permutation: &Permutation) -> Result<Self> {
+        let file_name = self.src_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .context("File name cannot be converted to &str")?;
+        let (stem, extension) = file_name.split_at(file_name.rfind('.').context("File has no extension")?);
+        self.spv_path = self.src_path.with_file_name(format!("{}.{}{}.spv", stem, permutation.name, extension));
+        self.defines = permutation.macro_definitions();
+        Ok(self)
+    }
+}
+
+// Also used by the shader reloader
+const PERMUTATION_MANIFEST: &str = "./src/rendering/shader_reload/permutations.rs";
+include!("src/rendering/shader_reload/permutations.rs");
+
+fn load_permutations() -> Result<Vec<ShaderData>> {
+    println!("cargo:rerun-if-changed={}", PERMUTATIONS);
+    if !Path::new(PERMUTATIONS).exists() {
+        return Ok(Vec::new());
+    }
+
+    read_permutations(Path::new(PERMUTATIONS))?
+        .iter()
+        .map(|p| ShaderData::load(Path::new("./src").join(&p.shader))?.permutation(p))
+        .collect()
+}
+
+// Also used by the shader reloader
+const INCLUDES: &str = "./src/rendering/shader_reload/includes.rs";
+include!("src/rendering/shader_reload/includes.rs");
+
+// Returns the SPIR-V and every file that was included
+fn compile(compiler: &mut shaderc::Compiler, shader: &ShaderData) -> Result<(Vec<u8>, Vec<PathBuf>)> {
+    let included = Rc::new(RefCell::new(Vec::new()));
+
+    let mut options = shaderc::CompileOptions::new().context("Unable to create shader compile options")?;
+    for (name, value) in &shader.defines {
+        options.add_macro_definition(name, value.as_deref());
+    }
+    set_include_callback(&mut options, PathBuf::from(INCLUDE_DIR), included.clone());
+
+    let compiled = compiler.compile_into_spirv(
+        &shader.src,
+        shader.kind,
+        &shader.src_path.to_str().unwrap(),
+        "main",
+        Some(&options),
+    )?;
+
+    let included = included.borrow().clone();
+    Ok((compiled.as_binary_u8().to_vec(), included))
+}
+
+// Module name for a shader in the generated interface, shader.vert -> shader_vert
+fn module_name(spv_path: &Path) -> String {
+    let file_name = spv_path.file_name().unwrap().to_string_lossy();
+    file_name
+        .trim_end_matches(".spv")
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect()
+}
+
+// Constant name for a uniform block type, SpriteSheet -> SPRITE_SHEET_SIZE
+fn size_constant(block: &str) -> String {
+    let mut name = String::new();
+    for (i, c) in block.chars().enumerate() {
+        if c.is_ascii_uppercase() && i > 0 {
+            name.push('_');
+        }
+        name.push(c.to_ascii_uppercase());
+    }
+    name + "_SIZE"
+}
+
+// The variants of wgpu::VertexFormat shaders can read, written into the
+// generated interface by name
+#[derive(Clone, Copy, Debug)]
+enum VertexFormat {
+    Uint,
+    Uint2,
+    Uint3,
+    Uint4,
+    Int,
+    Int2,
+    Int3,
+    Int4,
+    Float,
+    Float2,
+    Float3,
+    Float4,
+}
+
+// Also used by the runtime checks of reloaded shaders
+const REFLECT: &str = "./src/rendering/shader_interface/reflect.rs";
+include!("src/rendering/shader_interface/reflect.rs");
+
+// Reflects the vertex inputs and uniform blocks of a compiled shader into a
+// module of the generated shader interface
+fn reflect(shader: &ShaderData, spirv: &[u8], out: &mut String) -> Result<()> {
+    let module = spirv_reflect::ShaderModule::load_u8_data(spirv).map_err(|e| anyhow!(e))?;
+
+    writeln!(out, "pub mod {} {{", module_name(&shader.spv_path))?;
+
+    if let shaderc::ShaderKind::Vertex = shader.kind {
+        let inputs = vertex_inputs(&module)?;
+
+        writeln!(out, "    // Location and format of every vertex input")?;
+        writeln!(out, "    pub const INPUTS: &[(u32, wgpu::VertexFormat)] = &[")?;
+        for (location, format) in inputs {
+            writeln!(out, "        ({}, wgpu::VertexFormat::{:?}),", location, format)?;
+        }
+        writeln!(out, "    ];")?;
+    }
+
+    for binding in module.enumerate_descriptor_bindings(None).map_err(|e| anyhow!(e))? {
+        match binding.descriptor_type {
+            spirv_reflect::types::ReflectDescriptorType::UniformBuffer => {}
+            _ => continue,
+        }
+        let block = binding.type_description
+            .as_ref()
+            .map(|t| t.type_name.clone())
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| format!("Set{}Binding{}", binding.set, binding.binding));
+        writeln!(out, "    // Uniform block {} at set {}, binding {}", block, binding.set, binding.binding)?;
+        writeln!(out, "    pub const {}: usize = {};", size_constant(&block), binding.block.size)?;
+    }
+
+    writeln!(out, "}}")?;
+    Ok(())
 }
 
 fn main() -> Result<()> {
@@ -45,15 +188,23 @@ fn main() -> Result<()> {
     ];
 
     // This could be parallelized
-    let shaders = shader_paths
+    let mut shaders = shader_paths
         .iter_mut()
         .flatten()
         .map(|glob_result| ShaderData::load(glob_result?))
         .collect::<Vec<Result<_>>>()
         .into_iter()
         .collect::<Result<Vec<_>>>()?;
+    shaders.extend(load_permutations()?);
+
+    // New files in the include directory can change which file an include resolves to
+    println!("cargo:rerun-if-changed={}", INCLUDE_DIR);
+    println!("cargo:rerun-if-changed={}", REFLECT);
+    println!("cargo:rerun-if-changed={}", INCLUDES);
+    println!("cargo:rerun-if-changed={}", PERMUTATION_MANIFEST);
 
     let mut compiler = shaderc::Compiler::new().context("Unable to create shader compiler")?;
+    let mut interface = String::from("// Generated by build.rs from the compiled shaders\n");
 
     // This can't be parallelized. The [shaderc::Compiler] is not
     // thread safe. Also, it creates a lot of resources. You could
@@ -66,15 +217,17 @@ fn main() -> Result<()> {
             "cargo:rerun-if-changed={}",
             shader.src_path.as_os_str().to_str().unwrap()
         );
-        let compiled = compiler.compile_into_spirv(
-            &shader.src,
-            shader.kind,
-            &shader.src_path.to_str().unwrap(),
-            "main",
-            None,
-        )?;
-        write(shader.spv_path, compiled.as_binary_u8())?;
+        let (compiled, included) = compile(&mut compiler, &shader)?;
+        for path in included {
+            println!("cargo:rerun-if-changed={}", path.display());
+        }
+        reflect(&shader, &compiled, &mut interface)
+            .with_context(|| format!("Failed to reflect {}", shader.src_path.display()))?;
+        write(&shader.spv_path, compiled)?;
     }
 
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR")?);
+    write(out_dir.join("shader_interface.rs"), interface)?;
+
     Ok(())
 }
@@ -0,0 +1,180 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::clock::Clock;
+use crate::sprite_sheet::{SpriteSheet, SpriteSheetHandle};
+
+/// Polls the files sprite sheets were loaded from and reports the sheets whose
+/// descriptor or image changed on disk
+pub struct AssetWatcher {
+    interval: Duration,
+    last_poll: Duration,
+    // Indexed by SpriteSheetHandle, every file of the sheet and when it was last modified
+    sheets: Vec<Vec<(PathBuf, Option<SystemTime>)>>,
+}
+
+impl AssetWatcher {
+    pub fn new(interval: Duration, clock: &dyn Clock) -> AssetWatcher {
+        AssetWatcher {
+            interval,
+            last_poll: clock.now(),
+            sheets: Vec::new(),
+        }
+    }
+
+    // Sheets built at runtime have no files and are never reported
+    fn files(sheet: &SpriteSheet) -> Vec<(PathBuf, Option<SystemTime>)> {
+        let mut files = Vec::new();
+        if let Some(path) = &sheet.path {
+            files.push(path.clone());
+            if sheet.image.is_none() {
+                files.push(sheet.image_path.clone());
+            }
+        }
+        files.into_iter().map(|path| {
+            let modified = modified(&path);
+            (path, modified)
+        }).collect()
+    }
+
+    // Starts watching sheets added since the last poll. Returns the sheets
+    // with a file that changed, at most once per interval.
+    pub fn poll(&mut self, clock: &dyn Clock, sheets: &[SpriteSheet]) -> Vec<SpriteSheetHandle> {
+        for sheet in &sheets[self.sheets.len().min(sheets.len())..] {
+            self.sheets.push(Self::files(sheet));
+        }
+
+        let now = clock.now();
+        if now - self.last_poll < self.interval {
+            return Vec::new();
+        }
+        self.last_poll = now;
+
+        let mut changed = Vec::new();
+        for (i, files) in self.sheets.iter_mut().enumerate() {
+            let mut dirty = false;
+            for (path, last_modified) in files.iter_mut() {
+                let current = modified(path);
+                if current != *last_modified {
+                    *last_modified = current;
+                    dirty = true;
+                }
+            }
+            if dirty {
+                changed.push(SpriteSheetHandle(i));
+            }
+        }
+        changed
+    }
+
+    // A reloaded descriptor may point to a different image
+    pub fn rewatch(&mut self, handle: SpriteSheetHandle, sheet: &SpriteSheet) {
+        if let Some(files) = self.sheets.get_mut(handle.0) {
+            *files = Self::files(sheet);
+        }
+    }
+}
+
+// None while the file is missing, e.g. in the middle of an editor replacing it
+pub fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    const INTERVAL: Duration = Duration::from_millis(500);
+
+    // A directory of its own for every test, they run in parallel
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hello-wgpu-assets-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_sheet(dir: &Path, image: &str) -> PathBuf {
+        let path = dir.join("sheet.ron");
+        let src = format!(r#"(image: "{}", frames: Grid(columns: 1, rows: 1), animations: [])"#, image);
+        std::fs::write(&path, src).unwrap();
+        std::fs::write(dir.join(image), b"not decoded by the watcher").unwrap();
+        path
+    }
+
+    // Rewrites the file until its modification time moves on, file systems
+    // with a coarse resolution need a moment
+    fn touch(path: &Path) {
+        let before = modified(path);
+        let contents = std::fs::read(path).unwrap();
+        while modified(path) == before {
+            std::thread::sleep(Duration::from_millis(10));
+            std::fs::write(path, &contents).unwrap();
+        }
+    }
+
+    #[test]
+    fn reports_changes_once_per_interval() {
+        let dir = temp_dir("interval");
+        let path = write_sheet(&dir, "sheet.png");
+        let sheets = vec![SpriteSheet::load(&path).unwrap()];
+        let mut clock = ManualClock::new();
+        let mut watcher = AssetWatcher::new(INTERVAL, &clock);
+
+        clock.advance(INTERVAL);
+        assert!(watcher.poll(&clock, &sheets).is_empty());
+
+        // Changes within the interval wait for the next poll after it
+        touch(&dir.join("sheet.png"));
+        clock.advance(INTERVAL / 2);
+        assert!(watcher.poll(&clock, &sheets).is_empty());
+        touch(&path);
+        clock.advance(INTERVAL / 2);
+        assert_eq!(watcher.poll(&clock, &sheets), vec![SpriteSheetHandle(0)]);
+
+        clock.advance(INTERVAL);
+        assert!(watcher.poll(&clock, &sheets).is_empty());
+    }
+
+    #[test]
+    fn reports_removed_files_and_ignores_runtime_sheets() {
+        let dir = temp_dir("removed");
+        let path = write_sheet(&dir, "sheet.png");
+        let mut runtime = SpriteSheet::load(&path).unwrap();
+        runtime.path = None;
+        let sheets = vec![runtime, SpriteSheet::load(&path).unwrap()];
+        let mut clock = ManualClock::new();
+        let mut watcher = AssetWatcher::new(INTERVAL, &clock);
+        assert!(watcher.poll(&clock, &sheets).is_empty());
+
+        std::fs::remove_file(dir.join("sheet.png")).unwrap();
+        clock.advance(INTERVAL);
+        assert_eq!(watcher.poll(&clock, &sheets), vec![SpriteSheetHandle(1)]);
+    }
+
+    #[test]
+    fn rewatches_the_files_of_a_reloaded_sheet() {
+        let dir = temp_dir("rewatch");
+        let path = write_sheet(&dir, "old.png");
+        let mut sheets = vec![SpriteSheet::load(&path).unwrap()];
+        let mut clock = ManualClock::new();
+        let mut watcher = AssetWatcher::new(INTERVAL, &clock);
+        assert!(watcher.poll(&clock, &sheets).is_empty());
+
+        // The descriptor now points to another image
+        write_sheet(&dir, "new.png");
+        touch(&path);
+        clock.advance(INTERVAL);
+        assert_eq!(watcher.poll(&clock, &sheets), vec![SpriteSheetHandle(0)]);
+        sheets[0] = SpriteSheet::load(&path).unwrap();
+        watcher.rewatch(SpriteSheetHandle(0), &sheets[0]);
+
+        touch(&dir.join("old.png"));
+        clock.advance(INTERVAL);
+        assert!(watcher.poll(&clock, &sheets).is_empty());
+
+        touch(&dir.join("new.png"));
+        clock.advance(INTERVAL);
+        assert_eq!(watcher.poll(&clock, &sheets), vec![SpriteSheetHandle(0)]);
+    }
+}
@@ -1,7 +1,3 @@
-use winit::{
-    event::*,
-};
-
 #[rustfmt::skip]
 pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
     1.0, 0.0, 0.0, 0.0,
@@ -12,6 +8,8 @@ pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
 
 pub struct Camera {
     pub center: cgmath::Vector2<f32>,
+    // Center at the start of the current tick, used for render interpolation
+    pub previous_center: cgmath::Vector2<f32>,
     pub aspect: f32,
     pub height: f32,
     pub znear: f32,
@@ -19,27 +17,221 @@ pub struct Camera {
 }
 
 impl Camera {
-    pub fn build_view_projection_matrix(&self) -> cgmath::Matrix4<f32> {
-        let proj = cgmath::ortho(-self.height * self.aspect / 2.0 + self.center.x, self.height * self.aspect / 2.0 + self.center.x, -self.height / 2.0 + self.center.y, self.height / 2.0 + self.center.y, self.znear, self.zfar);
+    pub fn build_view_projection_matrix(&self, alpha: f32) -> cgmath::Matrix4<f32> {
+        use cgmath::VectorSpace;
+        let center = self.previous_center.lerp(self.center, alpha);
+        let proj = cgmath::ortho(-self.height * self.aspect / 2.0 + center.x, self.height * self.aspect / 2.0 + center.x, -self.height / 2.0 + center.y, self.height / 2.0 + center.y, self.znear, self.zfar);
         return OPENGL_TO_WGPU_MATRIX * proj;
     }
+
+    // Matches the aspect ratio to the window. A minimized window has no
+    // size, the old aspect is kept for when it comes back.
+    pub fn resize(&mut self, width: u32, height: u32) {
+        if width > 0 && height > 0 {
+            self.aspect = width as f32 / height as f32;
+        }
+    }
+
+    // Half of the visible width and height
+    pub fn half_extents(&self) -> cgmath::Vector2<f32> {
+        cgmath::Vector2::new(self.height * self.aspect / 2.0, self.height / 2.0)
+    }
 }
 
-pub struct CameraController {}
+/// Area of the world the camera is allowed to show
+pub struct Bounds {
+    pub min: cgmath::Vector2<f32>,
+    pub max: cgmath::Vector2<f32>,
+}
+
+/// Moves the camera after a target. The target moves freely inside the dead
+/// zone, outside it the camera eases towards the target without overshooting.
+pub struct CameraFollow {
+    // Half width and height of the box around the center the target can move in
+    pub dead_zone: cgmath::Vector2<f32>,
+    // Roughly the seconds the camera takes to catch up
+    pub smooth_time: f32,
+    // Seconds of target movement to look ahead
+    pub look_ahead: f32,
+    pub bounds: Option<Bounds>,
+    velocity: cgmath::Vector2<f32>,
+}
 
-impl CameraController {
-    pub fn update_camera_with_controller(controller: &crate::controller::Controller, camera: &mut Camera) {
-        if controller.is_up_pressed {
-            camera.center.y = camera.center.y + controller.speed;
+impl CameraFollow {
+    pub fn new(dead_zone: cgmath::Vector2<f32>, smooth_time: f32, look_ahead: f32, bounds: Option<Bounds>) -> CameraFollow {
+        CameraFollow {
+            dead_zone,
+            smooth_time,
+            look_ahead,
+            bounds,
+            velocity: cgmath::Vector2::new(0.0, 0.0),
         }
-        if controller.is_down_pressed {
-            camera.center.y = camera.center.y - controller.speed;
+    }
+
+    pub fn update(&mut self, camera: &mut Camera, target: cgmath::Vector2<f32>, target_velocity: cgmath::Vector2<f32>, dt: f32) {
+        let focus = target + target_velocity * self.look_ahead;
+
+        // Only the part of the offset sticking out of the dead zone moves the camera
+        let offset = focus - camera.center;
+        let excess = cgmath::Vector2::new(
+            offset.x - offset.x.max(-self.dead_zone.x).min(self.dead_zone.x),
+            offset.y - offset.y.max(-self.dead_zone.y).min(self.dead_zone.y),
+        );
+        let goal = self.clamp(camera, camera.center + excess);
+
+        let (x, vx) = smooth_damp(camera.center.x, goal.x, self.velocity.x, self.smooth_time, dt);
+        let (y, vy) = smooth_damp(camera.center.y, goal.y, self.velocity.y, self.smooth_time, dt);
+        camera.center = self.clamp(camera, cgmath::Vector2::new(x, y));
+        self.velocity = cgmath::Vector2::new(vx, vy);
+    }
+
+    // Keeps the view inside the bounds, or centered on them if they are smaller than the view
+    fn clamp(&self, camera: &Camera, center: cgmath::Vector2<f32>) -> cgmath::Vector2<f32> {
+        let bounds = match &self.bounds {
+            Some(bounds) => bounds,
+            None => return center,
+        };
+        let half = camera.half_extents();
+        let clamp_axis = |value: f32, min: f32, max: f32, half: f32| {
+            if max - min < 2.0 * half {
+                (min + max) / 2.0
+            } else {
+                value.max(min + half).min(max - half)
+            }
+        };
+        cgmath::Vector2::new(
+            clamp_axis(center.x, bounds.min.x, bounds.max.x, half.x),
+            clamp_axis(center.y, bounds.min.y, bounds.max.y, half.y),
+        )
+    }
+}
+
+// Critically damped spring towards target, returns the new value and velocity.
+// Approximates the exponential of the closed form with a polynomial, see Game
+// Programming Gems 4, chapter 1.10.
+fn smooth_damp(current: f32, target: f32, velocity: f32, smooth_time: f32, dt: f32) -> (f32, f32) {
+    let omega = 2.0 / smooth_time.max(0.0001);
+    let x = omega * dt;
+    let decay = 1.0 / (1.0 + x + 0.48 * x * x + 0.235 * x * x * x);
+    let change = current - target;
+    let temp = (velocity + omega * change) * dt;
+    let velocity = (velocity - omega * temp) * decay;
+    (target + (change + temp) * decay, velocity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn camera() -> Camera {
+        Camera {
+            center: cgmath::Vector2::new(0.0, 0.0),
+            previous_center: cgmath::Vector2::new(0.0, 0.0),
+            aspect: 16.0 / 9.0,
+            height: 10.0,
+            znear: 0.1,
+            zfar: 100.0,
         }
-        if controller.is_right_pressed {
-            camera.center.x = camera.center.x + controller.speed;
+    }
+
+    fn follow(bounds: Option<Bounds>) -> CameraFollow {
+        CameraFollow::new(cgmath::Vector2::new(1.0, 1.0), 0.3, 0.5, bounds)
+    }
+
+    fn bounds(half_width: f32, half_height: f32) -> Option<Bounds> {
+        Some(Bounds {
+            min: cgmath::Vector2::new(-half_width, -half_height),
+            max: cgmath::Vector2::new(half_width, half_height),
+        })
+    }
+
+    fn still() -> cgmath::Vector2<f32> {
+        cgmath::Vector2::new(0.0, 0.0)
+    }
+
+    #[test]
+    fn target_moves_freely_inside_the_dead_zone() {
+        let mut camera = camera();
+        let mut follow = follow(None);
+        for _ in 0..100 {
+            follow.update(&mut camera, cgmath::Vector2::new(0.9, -0.9), still(), 0.01);
+        }
+        assert_eq!(camera.center, still());
+    }
+
+    #[test]
+    fn eases_towards_the_target_without_overshooting() {
+        let mut camera = camera();
+        let mut follow = follow(None);
+        let target = cgmath::Vector2::new(5.0, 0.0);
+
+        let mut previous = camera.center.x;
+        for _ in 0..300 {
+            follow.update(&mut camera, target, still(), 0.01);
+            assert!(camera.center.x >= previous);
+            assert!(camera.center.x <= 4.0 + 1e-4);
+            previous = camera.center.x;
         }
-        if controller.is_left_pressed {
-            camera.center.x = camera.center.x - controller.speed;
+        // Settles with the target on the edge of the dead zone
+        assert!((camera.center.x - 4.0).abs() < 1e-3);
+        assert_eq!(camera.center.y, 0.0);
+
+        // The first step only covers part of the way
+        let mut camera = self::camera();
+        let mut follow = self::follow(None);
+        follow.update(&mut camera, target, still(), 0.01);
+        assert!(camera.center.x > 0.0 && camera.center.x < 1.0);
+    }
+
+    #[test]
+    fn looks_ahead_of_a_moving_target() {
+        let mut camera = camera();
+        let mut follow = follow(None);
+        // Two units per second with half a second of look-ahead puts the
+        // focus one unit ahead of the target
+        let velocity = cgmath::Vector2::new(0.0, 2.0);
+        for _ in 0..300 {
+            follow.update(&mut camera, cgmath::Vector2::new(0.0, 3.0), velocity, 0.01);
         }
+        assert!((camera.center.y - 3.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn keeps_the_view_inside_the_bounds() {
+        let camera = camera();
+        let half = camera.half_extents();
+        let follow = follow(bounds(16.0, 9.0));
+
+        let clamped = follow.clamp(&camera, cgmath::Vector2::new(100.0, -100.0));
+        assert_eq!(clamped, cgmath::Vector2::new(16.0 - half.x, half.y - 9.0));
+        let inside = cgmath::Vector2::new(1.0, 2.0);
+        assert_eq!(follow.clamp(&camera, inside), inside);
+
+        // Bounds smaller than the view keep it centered on them
+        let follow = self::follow(bounds(2.0, 9.0));
+        assert_eq!(follow.clamp(&camera, cgmath::Vector2::new(5.0, 1.0)), cgmath::Vector2::new(0.0, 1.0));
+    }
+
+    #[test]
+    fn follows_up_to_the_bounds() {
+        let mut camera = camera();
+        let half = camera.half_extents();
+        let mut follow = follow(bounds(16.0, 9.0));
+        for _ in 0..300 {
+            follow.update(&mut camera, cgmath::Vector2::new(30.0, 0.0), still(), 0.01);
+            assert!(camera.center.x <= 16.0 - half.x);
+        }
+        assert!((camera.center.x - (16.0 - half.x)).abs() < 1e-4);
+    }
+
+    #[test]
+    fn resize_follows_the_window_and_ignores_minimizing() {
+        let mut camera = camera();
+
+        camera.resize(800, 800);
+        assert_eq!(camera.half_extents(), cgmath::Vector2::new(5.0, 5.0));
+
+        camera.resize(0, 0);
+        assert_eq!(camera.aspect, 1.0);
     }
 }
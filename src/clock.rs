@@ -0,0 +1,50 @@
+use std::time::{Duration, Instant};
+
+/// Source of time for game logic. Time is measured as a duration since the
+/// clock was started so that simulated clocks don't need an `Instant`.
+pub trait Clock {
+    fn now(&self) -> Duration;
+}
+
+/// Wall-clock time
+pub struct RealClock {
+    start: Instant,
+}
+
+impl RealClock {
+    pub fn new() -> RealClock {
+        RealClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Clock for RealClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// Clock that only moves when told to. Drives the simulation in fixed ticks
+/// and lets game logic be stepped by exact amounts without a window.
+pub struct ManualClock {
+    now: Duration,
+}
+
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock {
+            now: Duration::from_secs(0),
+        }
+    }
+
+    pub fn advance(&mut self, dt: Duration) {
+        self.now += dt;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        self.now
+    }
+}
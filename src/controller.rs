@@ -3,6 +3,7 @@ use winit::{
 };
 
 pub struct Controller {
+    // Units per second
     pub speed: f32,
     pub is_up_pressed: bool,
     pub is_down_pressed: bool,
@@ -21,6 +22,30 @@ impl Controller {
         }
     }
 
+    // Diagonals are normalized so they aren't faster than straight movement
+    pub fn velocity(&self) -> (f32, f32) {
+        let mut x = 0.0;
+        let mut y = 0.0;
+        if self.is_right_pressed {
+            x += 1.0;
+        }
+        if self.is_left_pressed {
+            x -= 1.0;
+        }
+        if self.is_up_pressed {
+            y += 1.0;
+        }
+        if self.is_down_pressed {
+            y -= 1.0;
+        }
+
+        let length = f32::sqrt(x * x + y * y);
+        if length == 0.0 {
+            return (0.0, 0.0);
+        }
+        (x / length * self.speed, y / length * self.speed)
+    }
+
     pub fn process_events(&mut self, event: &WindowEvent) -> bool {
         match event {
             WindowEvent::KeyboardInput {
@@ -1,152 +1,466 @@
-use std::time::{Instant, Duration};
+use anyhow::*;
+use std::rc::Rc;
+use std::time::Duration;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::Deserialize;
 use winit::{
     event::*,
 };
 
-use crate::camera::{Camera};
+use crate::camera::{Bounds, Camera, CameraFollow};
+use crate::clock::Clock;
 use crate::controller::Controller;
+use crate::sprite_sheet::{SpriteSheet, SpriteSheetHandle};
 
-mod ai;
+pub mod ai;
+mod animation_state;
+mod world;
+
+use animation_state::{AnimationGraph, AnimationParams, AnimationState, AnimationStateMachine, Transition};
+pub use world::{Animated, EntityId, PlayerControlled, Sprite, Transform, World};
+
+// Units per second
+const PLAYER_SPEED: f32 = 5.0;
+// The camera doesn't show anything outside of this
+const WORLD_HALF_SIZE: (f32, f32) = (16.0, 9.0);
 
 pub struct Animator {
     pub current_frame: usize,
+    // Playback speed multiplier, 0 pauses the animation
+    pub speed: f32,
     current_frame_index: usize,
-    last_frame_time: std::time::Instant,
+    last_frame_time: Duration,
+    // Direction of travel for PlaybackMode::PingPong
+    forward: bool,
+    finished: bool,
     animation: Animation
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AnimationEvent {
+    FrameChanged(usize),
+    // Sent once when a PlaybackMode::Once animation has played its last frame
+    Finished,
+}
+
 impl Animator {
-    pub fn new(animation: Animation) -> Animator {
+    pub fn new(animation: Animation, clock: &dyn Clock) -> Animator {
+        let current_frame_index = Self::first_frame_index(&animation);
         Animator {
-            current_frame_index: 0,
-            current_frame: animation.frames[0],
-            last_frame_time: std::time::Instant::now(),
+            current_frame_index,
+            current_frame: animation.frames[current_frame_index],
+            speed: 1.0,
+            last_frame_time: clock.now(),
+            forward: true,
+            finished: false,
             animation,
         }
     }
 
-    pub fn update(&mut self) -> Option<usize> {
-        if self.last_frame_time.elapsed() > self.animation.default_timing {
-            self.current_frame_index = (self.current_frame_index + 1) % self.animation.frames.len();
-            self.current_frame = self.animation.frames[self.current_frame_index];
-            self.last_frame_time = Instant::now();
+    fn first_frame_index(animation: &Animation) -> usize {
+        match animation.mode {
+            PlaybackMode::Reverse => animation.frames.len() - 1,
+            _ => 0,
+        }
+    }
+
+    // Switches to another animation, keeping the current frame index when the
+    // new animation is long enough
+    pub fn set_animation(&mut self, animation: Animation) {
+        if self.current_frame_index >= animation.frames.len() {
+            self.current_frame_index = Self::first_frame_index(&animation);
+        }
+        self.current_frame = animation.frames[self.current_frame_index];
+        self.forward = true;
+        self.finished = false;
+        self.animation = animation;
+    }
+
+    // Starts another animation from its first frame
+    pub fn play(&mut self, animation: Animation, clock: &dyn Clock) {
+        self.current_frame_index = Self::first_frame_index(&animation);
+        self.current_frame = animation.frames[self.current_frame_index];
+        self.last_frame_time = clock.now();
+        self.forward = true;
+        self.finished = false;
+        self.animation = animation;
+    }
 
-            return Some(self.current_frame_index);
+    pub fn update(&mut self, clock: &dyn Clock) -> Option<AnimationEvent> {
+        let now = clock.now();
+        if self.finished || self.speed <= 0.0 {
+            // Don't let paused time pile up, the animation resumes where it was
+            self.last_frame_time = now;
+            return None;
         }
-        None
+
+        // Rounded to whole nanoseconds, Duration::div_f32 would round exact
+        // timings up and change frames a tick late
+        let timing = self.animation.timing(self.current_frame_index);
+        let timing = Duration::from_nanos((timing.as_nanos() as f64 / self.speed as f64).round() as u64);
+        if now - self.last_frame_time < timing {
+            return None;
+        }
+        self.last_frame_time += timing;
+
+        let len = self.animation.frames.len();
+        self.current_frame_index = match self.animation.mode {
+            PlaybackMode::Loop => (self.current_frame_index + 1) % len,
+            PlaybackMode::Reverse => (self.current_frame_index + len - 1) % len,
+            PlaybackMode::Once => {
+                if self.current_frame_index + 1 == len {
+                    self.finished = true;
+                    return Some(AnimationEvent::Finished);
+                }
+                self.current_frame_index + 1
+            }
+            PlaybackMode::PingPong => {
+                if len == 1 {
+                    0
+                } else {
+                    if self.forward && self.current_frame_index + 1 == len {
+                        self.forward = false;
+                    } else if !self.forward && self.current_frame_index == 0 {
+                        self.forward = true;
+                    }
+                    if self.forward { self.current_frame_index + 1 } else { self.current_frame_index - 1 }
+                }
+            }
+        };
+        self.current_frame = self.animation.frames[self.current_frame_index];
+
+        Some(AnimationEvent::FrameChanged(self.current_frame_index))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub enum PlaybackMode {
+    Loop,
+    // Plays through once and holds the last frame
+    Once,
+    // Bounces between the first and the last frame
+    PingPong,
+    // Loops from the last frame to the first
+    Reverse,
+}
+
+impl Default for PlaybackMode {
+    fn default() -> Self {
+        PlaybackMode::Loop
     }
 }
 
 #[derive(Clone)]
 pub struct Animation {
+    pub name: String,
     pub frames: Vec<usize>,
     pub default_timing: std::time::Duration,
+    // Per-frame durations, falls back to default_timing when empty
+    pub frame_timings: Vec<std::time::Duration>,
+    pub mode: PlaybackMode,
 }
 
-#[derive(Clone, PartialEq)]
-pub enum Direction {
-    S = 0,
-    //SW,
-    W = 1,
-    //NW,
-    N = 2,
-    //NE,
-    E = 3,
-    //SE,
+impl Animation {
+    pub fn timing(&self, index: usize) -> Duration {
+        self.frame_timings.get(index).copied().unwrap_or(self.default_timing)
+    }
 }
 
-pub struct Instance {
-    pub position: cgmath::Vector3<f32>,
-    pub direction: Direction,
-    pub frame: u32,
-    pub animator: Animator
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    S,
+    SW,
+    W,
+    NW,
+    N,
+    NE,
+    E,
+    SE,
 }
 
-impl Instance {
-    fn get_direction_from_velocity(velocity: (f32, f32)) -> Direction {
-        if velocity.0*velocity.0 > velocity.1*velocity.1 {
-            if velocity.0 < 0.0 { 
-                return Direction::E;
-            }
-            else {
-                return Direction::W;
-            }
+impl Direction {
+    pub const CARDINAL: [Direction; 4] = [Direction::S, Direction::W, Direction::N, Direction::E];
+    pub const DIAGONAL: [Direction; 4] = [Direction::SW, Direction::NW, Direction::NE, Direction::SE];
+    pub const ALL: [Direction; 8] = [
+        Direction::S, Direction::SW, Direction::W, Direction::NW,
+        Direction::N, Direction::NE, Direction::E, Direction::SE,
+    ];
+
+    // Suffix of the animation clips for this direction, e.g. "run_SW"
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Direction::S => "S",
+            Direction::SW => "SW",
+            Direction::W => "W",
+            Direction::NW => "NW",
+            Direction::N => "N",
+            Direction::NE => "NE",
+            Direction::E => "E",
+            Direction::SE => "SE",
         }
-        else {
-            if velocity.1 < 0.0 { 
-                return Direction::S;
-            }
-            else {
-                return Direction::N;
-            }
+    }
+
+    // The direction seen in a horizontal mirror
+    pub fn mirrored(&self) -> Direction {
+        match self {
+            Direction::SW => Direction::SE,
+            Direction::W => Direction::E,
+            Direction::NW => Direction::NE,
+            Direction::NE => Direction::NW,
+            Direction::E => Direction::W,
+            Direction::SE => Direction::SW,
+            d => *d,
+        }
+    }
+
+    // Angle in radians, counter-clockwise from east with y pointing up
+    fn angle(&self) -> f32 {
+        use std::f32::consts::FRAC_PI_4;
+        let steps = match self {
+            Direction::E => 0.0,
+            Direction::NE => 1.0,
+            Direction::N => 2.0,
+            Direction::NW => 3.0,
+            Direction::W => 4.0,
+            Direction::SW => 5.0,
+            Direction::S => 6.0,
+            Direction::SE => 7.0,
+        };
+        steps * FRAC_PI_4
+    }
+
+    // Cardinal direction used for sheets without diagonals. Diagonals lie
+    // exactly between two, they keep facing sideways.
+    pub fn nearest_cardinal(&self) -> Direction {
+        match self {
+            Direction::SW | Direction::NW => Direction::W,
+            Direction::NE | Direction::SE => Direction::E,
+            d => *d,
         }
     }
+
+    // Picks the direction closest to the velocity. The current direction is
+    // kept until the velocity is more than `hysteresis` radians past the edge
+    // of its sector, so moving along a boundary doesn't flicker.
+    pub fn from_velocity(velocity: (f32, f32), current: Direction, eight_way: bool, hysteresis: f32) -> Direction {
+        let angle = velocity.1.atan2(velocity.0);
+        let candidates: &[Direction] = if eight_way { &Direction::ALL } else { &Direction::CARDINAL };
+        let half_sector = std::f32::consts::PI / candidates.len() as f32;
+
+        if candidates.contains(&current) && angle_between(angle, current.angle()) <= half_sector + hysteresis {
+            return current;
+        }
+
+        *candidates
+            .iter()
+            .min_by(|a, b| {
+                angle_between(angle, a.angle())
+                    .partial_cmp(&angle_between(angle, b.angle()))
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            })
+            .unwrap()
+    }
+}
+
+// Smallest absolute difference between two angles
+fn angle_between(a: f32, b: f32) -> f32 {
+    use std::f32::consts::PI;
+    let d = (a - b).rem_euclid(2.0 * PI);
+    d.min(2.0 * PI - d)
 }
 
 pub struct GameState {
-    pub last_frame: Instant,
+    // Seed of all randomness in the simulation, the same seed reproduces the same run
+    pub seed: u64,
+    pub last_update: Duration,
     pub time_delta: Option<Duration>,
     pub last_cursor: Option<(u32, u32)>,
     pub current_sprite_frame: u32,
-    pub sprite_frame_count: u32,
-    pub last_sprite_frame_time: Instant,
+    // Every sheet used by the scene, sprites refer to them by handle
+    pub sprite_sheets: Vec<SpriteSheet>,
+    // Bumped whenever the sheets are replaced as a whole, so the renderer
+    // knows to upload all of them again
+    pub sheets_generation: u32,
     pub camera: Camera,
-    pub instances: Vec<Instance>,
-    pub ai_controllers: Vec<ai::AIController>,
+    pub camera_follow: CameraFollow,
+    // Entity the camera follows, the player unless changed
+    pub camera_target: Option<EntityId>,
+    pub world: World,
+    // Seeds the AI of spawned characters
+    rng: ChaCha8Rng,
     pub controller: Controller,
-    pub animations: Vec<Animation>,
+    pub animation_graph: Rc<AnimationGraph>,
+    // Radians past a direction boundary before the facing changes
+    pub direction_hysteresis: f32,
+    // False while the GPU crowd moves the AI characters. Their AI and
+    // animations keep running here.
+    pub move_ai: bool,
+    // Events of the animators during the last tick, e.g. AnimationEvent::Finished
+    // for gameplay to react to a clip that played through
+    pub animation_events: Vec<(EntityId, AnimationEvent)>,
 }
 
 
 impl GameState {
-    pub fn new () -> GameState {
+    pub fn new (clock: &dyn Clock, seed: u64, sprite_sheet: SpriteSheet) -> Result<GameState> {
         let camera = Camera {
             center: cgmath::Vector2::new(0.0, 0.0),
+            previous_center: cgmath::Vector2::new(0.0, 0.0),
             height: 6.0,
             aspect: 16.0/9.0,
             znear: -1.0,
             zfar: 100.0,
         };
 
-        
-        let mut animations = Vec::new();
+        let animation_graph = Rc::new(Self::build_animation_graph(&sprite_sheet)?);
 
-        animations.push(Animation { frames: (0..5).collect(), default_timing: std::time::Duration::from_millis(100) });
-        animations.push(Animation { frames: (6..11).collect(), default_timing: std::time::Duration::from_millis(100) });
-        animations.push(Animation { frames: (12..17).collect(), default_timing: std::time::Duration::from_millis(100) });
-        animations.push(Animation { frames: (18..23).collect(), default_timing: std::time::Duration::from_millis(100) });
+        let mut game = GameState {
+            seed,
+            last_update: clock.now(),
+            time_delta: None,
+            last_cursor: Some((0, 0)),
+            current_sprite_frame: 0,
+            sprite_sheets: vec![sprite_sheet],
+            sheets_generation: 0,
+            camera,
+            camera_follow: CameraFollow::new(
+                cgmath::Vector2::new(1.0, 0.75),
+                0.3,
+                0.25,
+                Some(Bounds {
+                    min: cgmath::Vector2::new(-WORLD_HALF_SIZE.0, -WORLD_HALF_SIZE.1),
+                    max: cgmath::Vector2::new(WORLD_HALF_SIZE.0, WORLD_HALF_SIZE.1),
+                }),
+            ),
+            camera_target: None,
+            world: World::new(),
+            rng: ChaCha8Rng::seed_from_u64(seed),
+            controller: Controller::new(PLAYER_SPEED),
+            animation_graph,
+            direction_hysteresis: 10f32.to_radians(),
+            move_ai: true,
+            animation_events: Vec::new(),
+        };
 
-        let mut instances: Vec<Instance> = Vec::<Instance>::new();
-        let mut ai_controllers = Vec::new();
+        let position = cgmath::Vector3 { x: 0.0, y: 0.0, z: 0.0 };
+        let player = game.spawn_player(clock, SpriteSheetHandle(0), position, Direction::CARDINAL[0]);
+        game.camera_target = Some(player);
+        for i in 1..20 {
+            game.spawn_character(clock, SpriteSheetHandle(0), position, Direction::CARDINAL[i % 4], true);
+        }
 
-        for i in 0..20 {
-            instances.push(
-                Instance {
-                    position: cgmath::Vector3 { x: 0.0, y: 0.0, z: 0.0 },
-                    direction: Direction::N,
-                    frame: 0,
-                    animator: Animator::new(animations[i % 4].clone())
-                }
-            );
+        Ok(game)
+    }
+
+    // A character moved by the keyboard instead of the AI
+    pub fn spawn_player(
+        &mut self,
+        clock: &dyn Clock,
+        sprite_sheet: SpriteSheetHandle,
+        position: cgmath::Vector3<f32>,
+        direction: Direction,
+    ) -> EntityId {
+        let id = self.spawn_character(clock, sprite_sheet, position, direction, false);
+        self.world.players.insert(id, PlayerControlled);
+        id
+    }
 
-            ai_controllers.push(ai::AIController::new());
+    // A character animated by the animation graph, optionally walking around on its own
+    pub fn spawn_character(
+        &mut self,
+        clock: &dyn Clock,
+        sprite_sheet: SpriteSheetHandle,
+        position: cgmath::Vector3<f32>,
+        direction: Direction,
+        ai: bool,
+    ) -> EntityId {
+        let id = self.world.spawn();
+        let state = AnimationStateMachine::new(self.animation_graph.clone(), direction);
+
+        self.world.transforms.insert(id, Transform::at(position));
+        self.world.sprites.insert(id, Sprite::new(sprite_sheet, 0));
+        self.world.animations.insert(id, Animated {
+            direction,
+            animator: Animator::new(state.initial_animation(), clock),
+            state,
+        });
+        if ai {
+            self.world.ai_controllers.insert(id, ai::AIController::new(clock, self.rng.gen()));
         }
-        
 
+        id
+    }
 
-        GameState {
-            last_frame: Instant::now(),
-            time_delta: Some(Instant::now().elapsed()),
-            last_cursor: Some((0, 0)),
-            current_sprite_frame: 0,
-            sprite_frame_count: 24,
-            last_sprite_frame_time: Instant::now(),
-            camera,
-            instances,
-            ai_controllers,
-            controller: Controller::new(0.2),
-            animations,
+    // Removes the entity and all its components. The camera stops following
+    // it if it was the camera target.
+    pub fn despawn(&mut self, id: EntityId) -> bool {
+        if self.camera_target == Some(id) {
+            self.camera_target = None;
         }
+        self.world.despawn(id)
+    }
+
+    fn build_animation_graph(sprite_sheet: &SpriteSheet) -> Result<AnimationGraph> {
+        AnimationGraph::new(
+            sprite_sheet,
+            vec![
+                AnimationState { name: "idle", preserve_phase: false },
+                AnimationState { name: "run", preserve_phase: true },
+            ],
+            vec![
+                Transition { from: "idle", to: "run", condition: |p| p.speed > 0.0, preserve_phase: false },
+                Transition { from: "run", to: "idle", condition: |p| p.speed == 0.0, preserve_phase: false },
+            ],
+        )
+    }
+
+    // Replaces a sheet that changed on disk. Entities keep their state and
+    // frame index. The animation graph is built from the first sheet, so
+    // reloading it rebuilds the graph and fails if clips went missing.
+    pub fn reload_sprite_sheet(&mut self, handle: SpriteSheetHandle, sprite_sheet: SpriteSheet) -> Result<()> {
+        if handle == SpriteSheetHandle(0) {
+            let graph = Rc::new(Self::build_animation_graph(&sprite_sheet)?);
+            for animated in self.world.animations.values_mut() {
+                if !graph.is_eight_way() {
+                    animated.direction = animated.direction.nearest_cardinal();
+                }
+                animated.state.set_graph(graph.clone(), animated.direction, &mut animated.animator);
+            }
+            self.animation_graph = graph;
+        }
+
+        self.sprite_sheets[handle.0] = sprite_sheet;
+        Ok(())
+    }
+
+    // Replaces all sheets with one atlas so the whole scene is drawn in a
+    // single batch. The packed sheet isn't hot reloaded.
+    pub fn pack_sprite_sheets(&mut self, page_size: u32) -> Result<()> {
+        let (sprite_sheet, offsets) = SpriteSheet::pack(&self.sprite_sheets, page_size)?;
+        let graph = Rc::new(Self::build_animation_graph(&sprite_sheet)?);
+
+        for sprite in self.world.sprites.values_mut() {
+            sprite.frame += offsets[sprite.sprite_sheet.0];
+            sprite.sprite_sheet = SpriteSheetHandle(0);
+        }
+        for animated in self.world.animations.values_mut() {
+            animated.state.set_graph(graph.clone(), animated.direction, &mut animated.animator);
+        }
+
+        self.animation_graph = graph;
+        self.sprite_sheets = vec![sprite_sheet];
+        self.sheets_generation += 1;
+        Ok(())
+    }
+
+    pub fn add_sprite_sheet(&mut self, sprite_sheet: SpriteSheet) -> SpriteSheetHandle {
+        self.sprite_sheets.push(sprite_sheet);
+        SpriteSheetHandle(self.sprite_sheets.len() - 1)
+    }
+
+    pub fn sprite_sheet(&self, handle: SpriteSheetHandle) -> &SpriteSheet {
+        &self.sprite_sheets[handle.0]
     }
 
     // TODO: Actually return true if an event was consumed
@@ -174,47 +488,394 @@ impl GameState {
         false
     }
 
-    pub fn update(&mut self) {
-        let new_frame = Instant::now();
-        let dt = new_frame - self.last_frame;
-        self.time_delta = Some(dt);
-        self.last_frame = new_frame;
+    // Advances the simulation by one tick, up to the current time of the clock
+    pub fn update(&mut self, clock: &dyn Clock) {
+        let now = clock.now();
+        let dt = now - self.last_update;
+        self.last_update = now;
 
-        if self.controller.is_down_pressed {
-            self.instances[0].position[1] -= 0.05;
-        }
-        if self.controller.is_up_pressed {
-            self.instances[0].position[1] += 0.05;
+        for transform in self.world.transforms.values_mut() {
+            transform.previous_position = transform.position;
         }
-        if self.controller.is_left_pressed {
-            self.instances[0].position[0] -= 0.05;
+        self.camera.previous_center = self.camera.center;
+
+        self.update_players(clock, dt);
+        self.update_ai(clock, dt);
+
+        self.animation_events.clear();
+        for (id, animated) in self.world.animations.iter_mut() {
+            if let Some(event) = animated.animator.update(clock) {
+                self.animation_events.push((*id, event));
+            }
         }
-        if self.controller.is_right_pressed {
-            self.instances[0].position[0] += 0.05;
+
+        self.update_camera(dt);
+    }
+
+    fn update_camera(&mut self, dt: Duration) {
+        let dt = dt.as_secs_f32();
+        if dt <= 0.0 {
+            return;
         }
+        // Stays where it is once the target is despawned
+        let transforms = &self.world.transforms;
+        let transform = match self.camera_target.and_then(|id| transforms.get(&id)) {
+            Some(transform) => transform,
+            None => return,
+        };
+        let velocity = (transform.position - transform.previous_position).truncate() / dt;
+        self.camera_follow.update(&mut self.camera, transform.position.truncate(), velocity, dt);
+    }
 
+    // Moves and animates the players the same way the AI does, with the
+    // velocity coming from the keyboard
+    fn update_players(&mut self, clock: &dyn Clock, dt: Duration) {
+        let velocity = self.controller.velocity();
+        let speed = (velocity.0 * velocity.0 + velocity.1 * velocity.1).sqrt();
 
-        for i in 0..self.ai_controllers.len() {
-            let ai_controller = &mut self.ai_controllers[i];
-            
-            ai_controller.update();
+        for id in self.world.players.keys() {
+            // Players can't walk out of the area the camera shows
+            if let Some(transform) = self.world.transforms.get_mut(id) {
+                let x = transform.position[0] + velocity.0 * dt.as_secs_f32();
+                let y = transform.position[1] + velocity.1 * dt.as_secs_f32();
+                transform.position[0] = x.max(-WORLD_HALF_SIZE.0).min(WORLD_HALF_SIZE.0);
+                transform.position[1] = y.max(-WORLD_HALF_SIZE.1).min(WORLD_HALF_SIZE.1);
+            }
 
-            if let ai::State::Walking {velocity, ..} = ai_controller.state  {
-                self.instances[i].position[0] += velocity.0 * dt.as_secs_f32();
-                self.instances[i].position[1] += velocity.1 * dt.as_secs_f32();
-                let new_direction = Instance::get_direction_from_velocity(velocity);
-                if self.instances[i].direction != new_direction {
-                    self.instances[i].animator.animation = self.animations[new_direction.clone() as usize].clone();
-                    self.instances[i].direction = new_direction;
-                } 
-                self.instances[i].direction = Instance::get_direction_from_velocity(velocity);
+            if let Some(animated) = self.world.animations.get_mut(id) {
+                if speed > 0.0 {
+                    animated.direction = Direction::from_velocity(
+                        velocity,
+                        animated.direction,
+                        self.animation_graph.is_eight_way(),
+                        self.direction_hysteresis,
+                    );
+                }
+                let params = AnimationParams { speed, direction: animated.direction };
+                animated.state.update(&params, &mut animated.animator, clock);
             }
         }
+    }
+
+    fn update_ai(&mut self, clock: &dyn Clock, dt: Duration) {
+        for (id, ai_controller) in self.world.ai_controllers.iter_mut() {
+            ai_controller.update(clock);
+
+            let velocity = match ai_controller.state {
+                ai::State::Walking { velocity, .. } => Some(velocity),
+                _ => None,
+            };
+            let speed = velocity.map_or(0.0, |v| (v.0 * v.0 + v.1 * v.1).sqrt());
 
-        if dt.as_millis() > 0 {
-            for i in &mut self.instances {
-                i.animator.update();
+            if let (Some(velocity), Some(transform)) = (velocity, self.world.transforms.get_mut(id)) {
+                if self.move_ai {
+                    transform.position[0] += velocity.0 * dt.as_secs_f32();
+                    transform.position[1] += velocity.1 * dt.as_secs_f32();
+                }
             }
+
+            if let Some(animated) = self.world.animations.get_mut(id) {
+                if let Some(velocity) = velocity {
+                    animated.direction = Direction::from_velocity(
+                        velocity,
+                        animated.direction,
+                        self.animation_graph.is_eight_way(),
+                        self.direction_hysteresis,
+                    );
+                }
+                let params = AnimationParams { speed, direction: animated.direction };
+                animated.state.update(&params, &mut animated.animator, clock);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn animation(mode: PlaybackMode, frames: Vec<usize>) -> Animation {
+        Animation {
+            name: "test".to_string(),
+            frames,
+            default_timing: Duration::from_millis(100),
+            frame_timings: Vec::new(),
+            mode,
+        }
+    }
+
+    // Advances the clock and updates the animator, returns the new frame
+    fn step(animator: &mut Animator, clock: &mut ManualClock, millis: u64) -> usize {
+        clock.advance(Duration::from_millis(millis));
+        animator.update(clock);
+        animator.current_frame
+    }
+
+    #[test]
+    fn animator_advances_on_exact_frame_timing() {
+        let mut clock = ManualClock::new();
+        let mut animator = Animator::new(animation(PlaybackMode::Loop, vec![3, 4, 5]), &clock);
+        assert_eq!(animator.current_frame, 3);
+
+        assert_eq!(step(&mut animator, &mut clock, 99), 3);
+        assert_eq!(step(&mut animator, &mut clock, 1), 4);
+        assert_eq!(step(&mut animator, &mut clock, 100), 5);
+        assert_eq!(step(&mut animator, &mut clock, 100), 3);
+    }
+
+    #[test]
+    fn animator_uses_per_frame_timings() {
+        let mut clock = ManualClock::new();
+        let mut loop_animation = animation(PlaybackMode::Loop, vec![0, 1]);
+        loop_animation.frame_timings = vec![Duration::from_millis(50), Duration::from_millis(200)];
+        let mut animator = Animator::new(loop_animation, &clock);
+
+        assert_eq!(step(&mut animator, &mut clock, 50), 1);
+        assert_eq!(step(&mut animator, &mut clock, 199), 1);
+        assert_eq!(step(&mut animator, &mut clock, 1), 0);
+    }
+
+    #[test]
+    fn once_finishes_on_last_frame() {
+        let mut clock = ManualClock::new();
+        let mut animator = Animator::new(animation(PlaybackMode::Once, vec![0, 1, 2]), &clock);
+
+        assert_eq!(step(&mut animator, &mut clock, 100), 1);
+        assert_eq!(step(&mut animator, &mut clock, 100), 2);
+
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(animator.update(&clock), Some(AnimationEvent::Finished));
+        assert_eq!(animator.current_frame, 2);
+
+        // Finished is sent only once and the last frame is held
+        clock.advance(Duration::from_millis(1000));
+        assert_eq!(animator.update(&clock), None);
+        assert_eq!(animator.current_frame, 2);
+    }
+
+    #[test]
+    fn ping_pong_bounces_at_both_ends() {
+        let mut clock = ManualClock::new();
+        let mut animator = Animator::new(animation(PlaybackMode::PingPong, vec![0, 1, 2]), &clock);
+
+        let frames = (0..6).map(|_| step(&mut animator, &mut clock, 100)).collect::<Vec<_>>();
+        assert_eq!(frames, vec![1, 2, 1, 0, 1, 2]);
+    }
+
+    #[test]
+    fn reverse_loops_from_last_frame() {
+        let mut clock = ManualClock::new();
+        let mut animator = Animator::new(animation(PlaybackMode::Reverse, vec![0, 1, 2]), &clock);
+        assert_eq!(animator.current_frame, 2);
+
+        let frames = (0..4).map(|_| step(&mut animator, &mut clock, 100)).collect::<Vec<_>>();
+        assert_eq!(frames, vec![1, 0, 2, 1]);
+    }
+
+    #[test]
+    fn speed_scales_frame_timing() {
+        let mut clock = ManualClock::new();
+        let mut animator = Animator::new(animation(PlaybackMode::Loop, vec![0, 1, 2]), &clock);
+
+        animator.speed = 2.0;
+        assert_eq!(step(&mut animator, &mut clock, 50), 1);
+
+        animator.speed = 0.5;
+        assert_eq!(step(&mut animator, &mut clock, 199), 1);
+        assert_eq!(step(&mut animator, &mut clock, 1), 2);
+
+        // Paused time doesn't pile up, the animation resumes where it was
+        animator.speed = 0.0;
+        assert_eq!(step(&mut animator, &mut clock, 1000), 2);
+        animator.speed = 1.0;
+        assert_eq!(step(&mut animator, &mut clock, 99), 2);
+        assert_eq!(step(&mut animator, &mut clock, 1), 0);
+    }
+
+    // Position of every entity after simulating ticks from the seed
+    fn simulate(seed: u64, ticks: u32) -> Vec<(EntityId, cgmath::Vector3<f32>)> {
+        let mut clock = ManualClock::new();
+        let sprite_sheet = SpriteSheet::load(SpriteSheet::default_path()).unwrap();
+        let mut game = GameState::new(&clock, seed, sprite_sheet).unwrap();
+        for _ in 0..ticks {
+            clock.advance(Duration::from_millis(10));
+            game.update(&clock);
+        }
+        game.world.transforms.iter().map(|(id, t)| (*id, t.position)).collect()
+    }
+
+    #[test]
+    fn ai_keeps_animating_while_the_gpu_moves_it() {
+        let mut clock = ManualClock::new();
+        let sprite_sheet = SpriteSheet::load(SpriteSheet::default_path()).unwrap();
+        let mut game = GameState::new(&clock, 7, sprite_sheet).unwrap();
+        game.move_ai = false;
+        let start = game.world.transforms.iter().map(|(id, t)| (*id, t.position)).collect::<std::collections::BTreeMap<_, _>>();
+
+        // Every AI character starts walking after standing for two seconds
+        for _ in 0..=200 {
+            clock.advance(Duration::from_millis(10));
+            game.update(&clock);
+        }
+        for (id, ai_controller) in &game.world.ai_controllers {
+            assert!(matches!(ai_controller.state, ai::State::Walking { .. }));
+            assert_eq!(game.world.animations[id].state.state(), "run");
+            assert_eq!(game.world.transforms[id].position, start[id]);
+        }
+    }
+
+    // Unit velocity at the angle in degrees, counter-clockwise from east
+    fn heading(degrees: f32) -> (f32, f32) {
+        let radians = degrees.to_radians();
+        (radians.cos(), radians.sin())
+    }
+
+    #[test]
+    fn four_way_directions_switch_at_the_sector_boundaries() {
+        assert_eq!(Direction::from_velocity(heading(44.0), Direction::S, false, 0.0), Direction::E);
+        assert_eq!(Direction::from_velocity(heading(46.0), Direction::S, false, 0.0), Direction::N);
+        assert_eq!(Direction::from_velocity(heading(134.0), Direction::S, false, 0.0), Direction::N);
+        assert_eq!(Direction::from_velocity(heading(136.0), Direction::S, false, 0.0), Direction::W);
+        assert_eq!(Direction::from_velocity(heading(-44.0), Direction::N, false, 0.0), Direction::E);
+        assert_eq!(Direction::from_velocity(heading(-46.0), Direction::N, false, 0.0), Direction::S);
+        // Diagonal input never yields a diagonal in a four-way graph
+        assert_eq!(Direction::from_velocity(heading(10.0), Direction::NE, false, 0.0), Direction::E);
+    }
+
+    #[test]
+    fn eight_way_directions_switch_at_the_sector_boundaries() {
+        assert_eq!(Direction::from_velocity(heading(22.0), Direction::S, true, 0.0), Direction::E);
+        assert_eq!(Direction::from_velocity(heading(23.0), Direction::S, true, 0.0), Direction::NE);
+        assert_eq!(Direction::from_velocity(heading(67.0), Direction::S, true, 0.0), Direction::NE);
+        assert_eq!(Direction::from_velocity(heading(68.0), Direction::S, true, 0.0), Direction::N);
+        assert_eq!(Direction::from_velocity(heading(-112.0), Direction::N, true, 0.0), Direction::S);
+        assert_eq!(Direction::from_velocity(heading(-113.0), Direction::N, true, 0.0), Direction::SW);
+        assert_eq!(Direction::from_velocity(heading(180.0), Direction::N, true, 0.0), Direction::W);
+    }
+
+    #[test]
+    fn hysteresis_keeps_the_direction_past_the_boundary() {
+        let hysteresis = 10f32.to_radians();
+
+        // Four-way, the boundary between E and N is at 45 degrees
+        assert_eq!(Direction::from_velocity(heading(54.0), Direction::E, false, hysteresis), Direction::E);
+        assert_eq!(Direction::from_velocity(heading(56.0), Direction::E, false, hysteresis), Direction::N);
+        assert_eq!(Direction::from_velocity(heading(36.0), Direction::N, false, hysteresis), Direction::N);
+        assert_eq!(Direction::from_velocity(heading(34.0), Direction::N, false, hysteresis), Direction::E);
+
+        // Eight-way, the boundary between E and NE is at 22.5 degrees
+        assert_eq!(Direction::from_velocity(heading(32.0), Direction::E, true, hysteresis), Direction::E);
+        assert_eq!(Direction::from_velocity(heading(33.0), Direction::E, true, hysteresis), Direction::NE);
+        assert_eq!(Direction::from_velocity(heading(13.0), Direction::NE, true, hysteresis), Direction::NE);
+        assert_eq!(Direction::from_velocity(heading(12.0), Direction::NE, true, hysteresis), Direction::E);
+
+        // A diagonal can't be kept in a four-way graph
+        assert_eq!(Direction::from_velocity(heading(50.0), Direction::NE, false, hysteresis), Direction::N);
+    }
+
+    #[test]
+    fn nearest_cardinal_faces_diagonals_sideways() {
+        for direction in Direction::CARDINAL.iter() {
+            assert_eq!(direction.nearest_cardinal(), *direction);
         }
+        assert_eq!(Direction::SW.nearest_cardinal(), Direction::W);
+        assert_eq!(Direction::NW.nearest_cardinal(), Direction::W);
+        assert_eq!(Direction::NE.nearest_cardinal(), Direction::E);
+        assert_eq!(Direction::SE.nearest_cardinal(), Direction::E);
+    }
+
+    #[test]
+    fn despawning_the_camera_target_stops_the_camera() {
+        let clock = ManualClock::new();
+        let sprite_sheet = SpriteSheet::load(SpriteSheet::default_path()).unwrap();
+        let mut game = GameState::new(&clock, 0, sprite_sheet).unwrap();
+        let player = game.camera_target.unwrap();
+
+        assert!(game.despawn(player));
+        assert_eq!(game.camera_target, None);
+        assert!(!game.despawn(player));
+        assert!(!game.world.players.contains_key(&player));
+    }
+
+    #[test]
+    fn players_move_and_face_with_the_input() {
+        let mut clock = ManualClock::new();
+        let sprite_sheet = SpriteSheet::load(SpriteSheet::default_path()).unwrap();
+        let mut game = GameState::new(&clock, 0, sprite_sheet).unwrap();
+        let player = game.camera_target.unwrap();
+        let tick = Duration::from_millis(10);
+        let position = |game: &GameState| game.world.transforms[&player].position;
+
+        // Standing still for long enough that every AI character walks
+        let start = position(&game);
+        for _ in 0..300 {
+            clock.advance(tick);
+            game.update(&clock);
+        }
+        assert!(!game.world.ai_controllers.contains_key(&player));
+        assert_eq!(position(&game), start);
+        assert_eq!(game.world.animations[&player].state.state(), "idle");
+
+        game.controller.is_right_pressed = true;
+        clock.advance(tick);
+        game.update(&clock);
+        let step = PLAYER_SPEED * tick.as_secs_f32();
+        assert!((position(&game).x - (start.x + step)).abs() < 1e-5);
+        assert_eq!(position(&game).y, start.y);
+        assert_eq!(game.world.animations[&player].direction, Direction::E);
+        assert_eq!(game.world.animations[&player].state.state(), "run");
+
+        // Diagonals are as fast as straight movement
+        let before = position(&game);
+        game.controller.is_right_pressed = false;
+        game.controller.is_left_pressed = true;
+        game.controller.is_up_pressed = true;
+        clock.advance(tick);
+        game.update(&clock);
+        let moved = position(&game) - before;
+        assert!((moved.x + step / 2f32.sqrt()).abs() < 1e-5);
+        assert!((moved.y - step / 2f32.sqrt()).abs() < 1e-5);
+
+        game.controller.is_left_pressed = false;
+        clock.advance(tick);
+        game.update(&clock);
+        assert_eq!(game.world.animations[&player].direction, Direction::N);
+
+        // Letting go keeps the facing and goes back to idle
+        game.controller.is_up_pressed = false;
+        clock.advance(tick);
+        game.update(&clock);
+        assert_eq!(game.world.animations[&player].direction, Direction::N);
+        assert_eq!(game.world.animations[&player].state.state(), "idle");
+    }
+
+    #[test]
+    fn players_stay_inside_the_world() {
+        let mut clock = ManualClock::new();
+        let sprite_sheet = SpriteSheet::load(SpriteSheet::default_path()).unwrap();
+        let mut game = GameState::new(&clock, 0, sprite_sheet).unwrap();
+        let player = game.camera_target.unwrap();
+
+        game.controller.is_right_pressed = true;
+        game.controller.is_down_pressed = true;
+        for _ in 0..1000 {
+            clock.advance(Duration::from_millis(10));
+            game.update(&clock);
+        }
+        let position = game.world.transforms[&player].position;
+        assert_eq!((position.x, position.y), (WORLD_HALF_SIZE.0, -WORLD_HALF_SIZE.1));
+
+        // The camera stops at the same edge, with the player inside the view
+        let half = game.camera.half_extents();
+        assert!((game.camera.center.x - (WORLD_HALF_SIZE.0 - half.x)).abs() < 1e-3);
+        assert!((game.camera.center.y - (half.y - WORLD_HALF_SIZE.1)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn same_seed_replays_the_same_run() {
+        let positions = simulate(42, 1000);
+        assert_eq!(positions, simulate(42, 1000));
+        assert_ne!(positions, simulate(43, 1000));
     }
 }
@@ -1,50 +1,66 @@
-use rand::Rng;
-use std::time::{Duration, Instant};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::time::Duration;
+
+use crate::clock::Clock;
 
 pub enum State {
     Standing {
         duration: Duration,
-        started: Instant,
+        started: Duration,
     },
     Walking {
         duration: Duration,
-        started: Instant,
+        started: Duration,
         velocity: (f32, f32),
     },
 }
 
 pub struct AIController {
     pub state: State,
+    // Kept since ChaCha8Rng can't return it, the GPU crowd continues the
+    // same random stream from the key and the word position
+    key: [u8; 32],
+    rng: ChaCha8Rng,
 }
 
 impl AIController {
-    pub fn new() -> AIController {
+    pub fn new(clock: &dyn Clock, seed: u64) -> AIController {
+        let key = ChaCha8Rng::seed_from_u64(seed).gen();
         AIController {
             state: State::Standing {
                 duration: Duration::from_secs(2),
-                started: Instant::now(),
+                started: clock.now(),
             },
+            key,
+            rng: ChaCha8Rng::from_seed(key),
         }
     }
 
-    pub fn update(&mut self) {
+    // Key of the ChaCha8 stream and the number of 32-bit words drawn from it
+    pub fn random_stream(&self) -> ([u8; 32], u128) {
+        (self.key, self.rng.get_word_pos())
+    }
+
+    pub fn update(&mut self, clock: &dyn Clock) {
+        let now = clock.now();
         // Alternates between State::Standing and State::Walking
         match self.state {
             State::Standing { started, duration } => {
-                if started.elapsed() > duration {
-                    let mut rng = rand::thread_rng();
+                if now - started > duration {
+                    let rng = &mut self.rng;
                     self.state = State::Walking {
-                        started: Instant::now(),
+                        started: now,
                         duration: Duration::from_millis(800 + rng.gen::<u64>() % 1000),
                         velocity: (rng.gen::<f32>() * 2.0 - 1.0, rng.gen::<f32>() * 2.0 - 1.0),
                     }
                 }
             }
             State::Walking { started, duration, .. } => {
-                if started.elapsed() > duration {
-                    let mut rng = rand::thread_rng();
+                if now - started > duration {
+                    let rng = &mut self.rng;
                     self.state = State::Standing {
-                        started: Instant::now(),
+                        started: now,
                         duration: Duration::from_millis(800 + rng.gen::<u64>() % 1000),
                     }
                 }
@@ -52,3 +68,51 @@ impl AIController {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn walks_after_standing_for_two_seconds() {
+        let mut clock = ManualClock::new();
+        let mut ai = AIController::new(&clock, 1);
+
+        clock.advance(Duration::from_secs(2));
+        ai.update(&clock);
+        assert!(matches!(ai.state, State::Standing { .. }));
+
+        clock.advance(Duration::from_millis(10));
+        ai.update(&clock);
+        match ai.state {
+            State::Walking { started, duration, velocity } => {
+                assert_eq!(started, Duration::from_millis(2010));
+                assert!(duration >= Duration::from_millis(800) && duration < Duration::from_millis(1800));
+                assert!(velocity.0.abs() <= 1.0 && velocity.1.abs() <= 1.0);
+            }
+            State::Standing { .. } => panic!("Still standing after the initial wait"),
+        }
+    }
+
+    #[test]
+    fn stands_again_after_walking_duration() {
+        let mut clock = ManualClock::new();
+        let mut ai = AIController::new(&clock, 2);
+
+        clock.advance(Duration::from_millis(2010));
+        ai.update(&clock);
+        let duration = match ai.state {
+            State::Walking { duration, .. } => duration,
+            State::Standing { .. } => panic!("Still standing after the initial wait"),
+        };
+
+        clock.advance(duration);
+        ai.update(&clock);
+        assert!(matches!(ai.state, State::Walking { .. }));
+
+        clock.advance(Duration::from_millis(1));
+        ai.update(&clock);
+        assert!(matches!(ai.state, State::Standing { .. }));
+    }
+}
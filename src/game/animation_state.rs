@@ -0,0 +1,280 @@
+use anyhow::*;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::clock::Clock;
+use crate::sprite_sheet::SpriteSheet;
+
+use super::{Animation, Animator, Direction};
+
+/// Inputs the state machine decides transitions on
+pub struct AnimationParams {
+    pub speed: f32,
+    pub direction: Direction,
+}
+
+/// A logical state such as "idle" or "run". The clip that is played is picked
+/// by direction, so state "run" facing east plays the clip "run_E". Missing
+/// clips fall back to the horizontally mirrored direction, "run_W" for "run_E".
+pub struct AnimationState {
+    pub name: &'static str,
+    // Keep the frame index when the direction changes within this state
+    pub preserve_phase: bool,
+}
+
+pub struct Transition {
+    pub from: &'static str,
+    pub to: &'static str,
+    pub condition: fn(&AnimationParams) -> bool,
+    // Keep the frame index when moving to the new state
+    pub preserve_phase: bool,
+}
+
+/// States, transitions and clips shared by every instance using them
+pub struct AnimationGraph {
+    states: Vec<AnimationState>,
+    transitions: Vec<Transition>,
+    // Clip for every state and direction, and whether it has to be mirrored
+    clips: HashMap<(usize, Direction), (Animation, bool)>,
+    eight_way: bool,
+}
+
+impl AnimationGraph {
+    pub fn new(sprite_sheet: &SpriteSheet, states: Vec<AnimationState>, transitions: Vec<Transition>) -> Result<AnimationGraph> {
+        ensure!(!states.is_empty(), "Animation graph has no states");
+
+        let mut clips = HashMap::new();
+        for (i, state) in states.iter().enumerate() {
+            for direction in Direction::CARDINAL.iter() {
+                let clip = Self::resolve_clip(sprite_sheet, state.name, *direction).with_context(|| {
+                    format!("Sprite sheet has neither {} nor a mirrored clip for it", Self::clip_name(state.name, direction))
+                })?;
+                clips.insert((i, *direction), clip);
+            }
+        }
+
+        // Diagonals are only used if every state has them, otherwise the
+        // graph falls back to four directions
+        let mut diagonal_clips = HashMap::new();
+        for (i, state) in states.iter().enumerate() {
+            for direction in Direction::DIAGONAL.iter() {
+                if let Some(clip) = Self::resolve_clip(sprite_sheet, state.name, *direction) {
+                    diagonal_clips.insert((i, *direction), clip);
+                }
+            }
+        }
+        let eight_way = diagonal_clips.len() == states.len() * Direction::DIAGONAL.len();
+        if eight_way {
+            clips.extend(diagonal_clips);
+        }
+
+        for transition in &transitions {
+            for name in [transition.from, transition.to].iter() {
+                ensure!(states.iter().any(|s| s.name == *name), "Transition refers to unknown state {}", name);
+            }
+        }
+
+        Ok(AnimationGraph { states, transitions, clips, eight_way })
+    }
+
+    fn clip_name(state: &str, direction: &Direction) -> String {
+        format!("{}_{}", state, direction.suffix())
+    }
+
+    fn resolve_clip(sprite_sheet: &SpriteSheet, state: &str, direction: Direction) -> Option<(Animation, bool)> {
+        if let Some(clip) = sprite_sheet.animation(&Self::clip_name(state, &direction)) {
+            return Some((clip.clone(), false));
+        }
+        sprite_sheet
+            .animation(&Self::clip_name(state, &direction.mirrored()))
+            .map(|clip| (clip.clone(), true))
+    }
+
+    // Whether the sheet has clips for diagonal directions
+    pub fn is_eight_way(&self) -> bool {
+        self.eight_way
+    }
+
+    fn clip(&self, state: usize, direction: Direction) -> &(Animation, bool) {
+        // Cardinal directions are checked to exist in new(), diagonals only
+        // when the graph is eight-way
+        let direction = if self.eight_way { direction } else { direction.nearest_cardinal() };
+        &self.clips[&(state, direction)]
+    }
+}
+
+/// Per-instance position in an AnimationGraph. Starts in the first state.
+pub struct AnimationStateMachine {
+    graph: Rc<AnimationGraph>,
+    state: usize,
+    direction: Direction,
+    mirrored: bool,
+}
+
+impl AnimationStateMachine {
+    pub fn new(graph: Rc<AnimationGraph>, direction: Direction) -> AnimationStateMachine {
+        let mirrored = graph.clip(0, direction).1;
+        AnimationStateMachine {
+            graph,
+            state: 0,
+            direction,
+            mirrored,
+        }
+    }
+
+    pub fn state(&self) -> &'static str {
+        self.graph.states[self.state].name
+    }
+
+    // Switches to a graph rebuilt from a reloaded sheet, staying in the same
+    // state and keeping the frame index of the animator
+    pub fn set_graph(&mut self, graph: Rc<AnimationGraph>, direction: Direction, animator: &mut Animator) {
+        let (clip, mirrored) = graph.clip(self.state, direction).clone();
+        self.graph = graph;
+        self.direction = direction;
+        self.mirrored = mirrored;
+        animator.set_animation(clip);
+    }
+
+    pub fn initial_animation(&self) -> Animation {
+        self.graph.clip(self.state, self.direction).0.clone()
+    }
+
+    // The current clip is borrowed from the opposite direction and has to be
+    // drawn flipped horizontally
+    pub fn is_mirrored(&self) -> bool {
+        self.mirrored
+    }
+
+    // Evaluates the transitions out of the current state, first match wins,
+    // and switches the clip of the animator if the state or direction changed
+    pub fn update(&mut self, params: &AnimationParams, animator: &mut Animator, clock: &dyn Clock) {
+        let graph = self.graph.clone();
+        let current = graph.states[self.state].name;
+
+        let transition = graph.transitions
+            .iter()
+            .find(|t| t.from == current && (t.condition)(params));
+
+        let preserve_phase = match transition {
+            Some(transition) => {
+                self.state = graph.states.iter().position(|s| s.name == transition.to).unwrap();
+                transition.preserve_phase
+            }
+            None if self.direction != params.direction => graph.states[self.state].preserve_phase,
+            None => return,
+        };
+        self.direction = params.direction;
+
+        let (clip, mirrored) = graph.clip(self.state, self.direction).clone();
+        self.mirrored = mirrored;
+        if preserve_phase {
+            animator.set_animation(clip);
+        } else {
+            animator.play(clip, clock);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::clock::ManualClock;
+    use crate::game::PlaybackMode;
+    use crate::sprite_sheet::Frame;
+    use crate::texture::TextureOptions;
+
+    // West is left out to be mirrored from east
+    fn sprite_sheet() -> SpriteSheet {
+        let mut animations = Vec::new();
+        for (i, name) in ["idle_S", "run_S", "idle_N", "run_N", "idle_E", "run_E"].iter().enumerate() {
+            animations.push(Animation {
+                name: name.to_string(),
+                frames: vec![i * 2, i * 2 + 1],
+                default_timing: Duration::from_millis(100),
+                frame_timings: Vec::new(),
+                mode: PlaybackMode::Loop,
+            });
+        }
+        SpriteSheet {
+            path: None,
+            image_path: "test.png".into(),
+            image: None,
+            texture_options: TextureOptions::default(),
+            frames: vec![Frame { rect: [0.0, 0.0, 1.0, 1.0], pivot: [0.5, 0.5] }; 12],
+            animations,
+        }
+    }
+
+    fn graph() -> Rc<AnimationGraph> {
+        let graph = AnimationGraph::new(
+            &sprite_sheet(),
+            vec![
+                AnimationState { name: "idle", preserve_phase: false },
+                AnimationState { name: "run", preserve_phase: true },
+            ],
+            vec![
+                Transition { from: "idle", to: "run", condition: |p| p.speed > 0.0, preserve_phase: false },
+                Transition { from: "run", to: "idle", condition: |p| p.speed == 0.0, preserve_phase: false },
+            ],
+        );
+        Rc::new(graph.unwrap())
+    }
+
+    #[test]
+    fn follows_speed_between_idle_and_run() {
+        let clock = ManualClock::new();
+        let mut state = AnimationStateMachine::new(graph(), Direction::S);
+        let mut animator = Animator::new(state.initial_animation(), &clock);
+        assert_eq!(state.state(), "idle");
+        assert_eq!(animator.current_frame, 0);
+
+        state.update(&AnimationParams { speed: 1.0, direction: Direction::S }, &mut animator, &clock);
+        assert_eq!(state.state(), "run");
+        assert_eq!(animator.current_frame, 2);
+
+        state.update(&AnimationParams { speed: 0.0, direction: Direction::S }, &mut animator, &clock);
+        assert_eq!(state.state(), "idle");
+        assert_eq!(animator.current_frame, 0);
+    }
+
+    #[test]
+    fn mirrors_missing_directions() {
+        let clock = ManualClock::new();
+        let mut state = AnimationStateMachine::new(graph(), Direction::E);
+        let mut animator = Animator::new(state.initial_animation(), &clock);
+        assert!(!state.is_mirrored());
+
+        state.update(&AnimationParams { speed: 1.0, direction: Direction::W }, &mut animator, &clock);
+        assert_eq!(state.state(), "run");
+        assert!(state.is_mirrored());
+        assert_eq!(animator.current_frame, 10);
+    }
+
+    #[test]
+    fn four_way_graph_plays_diagonals_sideways() {
+        let clock = ManualClock::new();
+        let mut state = AnimationStateMachine::new(graph(), Direction::NE);
+        let mut animator = Animator::new(state.initial_animation(), &clock);
+        assert!(!graph().is_eight_way());
+        assert!(!state.is_mirrored());
+        assert_eq!(animator.current_frame, 8);
+
+        state.update(&AnimationParams { speed: 1.0, direction: Direction::SW }, &mut animator, &clock);
+        assert!(state.is_mirrored());
+        assert_eq!(animator.current_frame, 10);
+    }
+
+    #[test]
+    fn rejects_graph_with_missing_clips() {
+        let mut sheet = sprite_sheet();
+        sheet.animations.retain(|a| a.name != "run_N");
+        let graph = AnimationGraph::new(
+            &sheet,
+            vec![AnimationState { name: "run", preserve_phase: true }],
+            Vec::new(),
+        );
+        assert!(graph.is_err());
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::sprite_sheet::SpriteSheetHandle;
+
+use super::ai::AIController;
+use super::animation_state::AnimationStateMachine;
+use super::{Animator, Direction};
+
+/// Stable identifier of a spawned entity
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct EntityId(pub u64);
+
+// Ordered by id so that systems visit entities in the same order every run
+pub type Components<T> = BTreeMap<EntityId, T>;
+
+pub struct Transform {
+    pub position: cgmath::Vector3<f32>,
+    // Position at the start of the current tick, used for render interpolation
+    pub previous_position: cgmath::Vector3<f32>,
+    // Applied around the pivot of the frame
+    pub scale: cgmath::Vector2<f32>,
+    pub rotation: cgmath::Rad<f32>,
+}
+
+impl Transform {
+    pub fn at(position: cgmath::Vector3<f32>) -> Transform {
+        Transform {
+            position,
+            previous_position: position,
+            scale: cgmath::Vector2 { x: 1.0, y: 1.0 },
+            rotation: cgmath::Rad(0.0),
+        }
+    }
+}
+
+/// Drawn at the Transform of the entity, entities without one aren't drawn
+pub struct Sprite {
+    pub sprite_sheet: SpriteSheetHandle,
+    // Drawn when the entity has no Animated component
+    pub frame: usize,
+    // Mirroring on top of what the animation does, e.g. for W frames borrowed from E
+    pub flip_x: bool,
+    pub flip_y: bool,
+    // Multiplied with the texture color
+    pub tint: [f32; 4],
+    // Multiplied with the alpha of the tint, for fading in and out
+    pub opacity: f32,
+}
+
+impl Sprite {
+    pub fn new(sprite_sheet: SpriteSheetHandle, frame: usize) -> Sprite {
+        Sprite {
+            sprite_sheet,
+            frame,
+            flip_x: false,
+            flip_y: false,
+            tint: [1.0, 1.0, 1.0, 1.0],
+            opacity: 1.0,
+        }
+    }
+}
+
+/// Facing and the animation playing for it
+pub struct Animated {
+    pub direction: Direction,
+    pub animator: Animator,
+    pub state: AnimationStateMachine,
+}
+
+/// Moved by the keyboard Controller
+pub struct PlayerControlled;
+
+/// Entities are ids with any combination of components. Every component type
+/// has its own storage, systems join them by id.
+pub struct World {
+    next_id: u64,
+    entities: BTreeSet<EntityId>,
+    pub transforms: Components<Transform>,
+    pub sprites: Components<Sprite>,
+    pub animations: Components<Animated>,
+    pub ai_controllers: Components<AIController>,
+    pub players: Components<PlayerControlled>,
+}
+
+impl World {
+    pub fn new() -> World {
+        World {
+            next_id: 0,
+            entities: BTreeSet::new(),
+            transforms: Components::new(),
+            sprites: Components::new(),
+            animations: Components::new(),
+            ai_controllers: Components::new(),
+            players: Components::new(),
+        }
+    }
+
+    // An entity without components, add them by inserting into the storages
+    pub fn spawn(&mut self) -> EntityId {
+        let id = EntityId(self.next_id);
+        self.next_id += 1;
+        self.entities.insert(id);
+        id
+    }
+
+    // Removes the entity and all its components, returns false if it was already gone
+    pub fn despawn(&mut self, id: EntityId) -> bool {
+        self.transforms.remove(&id);
+        self.sprites.remove(&id);
+        self.animations.remove(&id);
+        self.ai_controllers.remove(&id);
+        self.players.remove(&id);
+        self.entities.remove(&id)
+    }
+
+    pub fn entities(&self) -> impl Iterator<Item = EntityId> + '_ {
+        self.entities.iter().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn spawn_full(world: &mut World) -> EntityId {
+        let clock = ManualClock::new();
+        let id = world.spawn();
+        world.transforms.insert(id, Transform::at(cgmath::Vector3::new(0.0, 0.0, 0.0)));
+        world.sprites.insert(id, Sprite::new(SpriteSheetHandle(0), 0));
+        world.ai_controllers.insert(id, AIController::new(&clock, id.0));
+        world.players.insert(id, PlayerControlled);
+        id
+    }
+
+    #[test]
+    fn despawn_removes_every_component() {
+        let mut world = World::new();
+        let kept = spawn_full(&mut world);
+        let id = spawn_full(&mut world);
+
+        assert!(world.despawn(id));
+        assert!(!world.despawn(id));
+        assert!(!world.transforms.contains_key(&id));
+        assert!(!world.sprites.contains_key(&id));
+        assert!(!world.animations.contains_key(&id));
+        assert!(!world.ai_controllers.contains_key(&id));
+        assert!(!world.players.contains_key(&id));
+        assert_eq!(world.entities().collect::<Vec<_>>(), vec![kept]);
+        assert!(world.transforms.contains_key(&kept));
+    }
+
+    #[test]
+    fn ids_are_never_reused() {
+        let mut world = World::new();
+        let first = world.spawn();
+        let second = world.spawn();
+        world.despawn(second);
+        world.despawn(first);
+
+        let third = world.spawn();
+        assert!(third != first && third != second);
+        assert!(third > second);
+    }
+
+    #[test]
+    fn iterates_in_spawn_order() {
+        let mut world = World::new();
+        let ids = (0..10).map(|_| spawn_full(&mut world)).collect::<Vec<_>>();
+        // Components inserted out of order are still visited by id
+        for id in ids.iter().rev() {
+            world.sprites.remove(id);
+            world.sprites.insert(*id, Sprite::new(SpriteSheetHandle(0), 0));
+        }
+        world.despawn(ids[4]);
+
+        let expected = ids.iter().copied().filter(|id| *id != ids[4]).collect::<Vec<_>>();
+        assert_eq!(world.entities().collect::<Vec<_>>(), expected);
+        assert_eq!(world.sprites.keys().copied().collect::<Vec<_>>(), expected);
+        assert_eq!(world.ai_controllers.keys().copied().collect::<Vec<_>>(), expected);
+    }
+}
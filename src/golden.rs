@@ -0,0 +1,266 @@
+use anyhow::*;
+use std::path::{Path, PathBuf};
+
+use crate::clock::ManualClock;
+use crate::game::{EntityId, GameState};
+use crate::rendering::headless::HeadlessState;
+use crate::sprite_sheet::SpriteSheet;
+
+// Checked-in reference images
+const REFERENCE_DIR: &str = "golden";
+// Actual and diff images of failed comparisons
+const OUTPUT_DIR: &str = "target/golden";
+
+const WIDTH: u32 = 640;
+const HEIGHT: u32 = 360;
+
+// Largest allowed difference of a single channel before a pixel counts as
+// mismatched. Leaves room for rounding differences between adapters.
+const TOLERANCE: u8 = 2;
+
+/// A deterministic scene with hand-placed entities
+struct Scene {
+    name: &'static str,
+    setup: fn(&mut GameState),
+}
+
+const SCENES: &[Scene] = &[
+    Scene { name: "frame_grid", setup: frame_grid },
+    Scene { name: "overlap", setup: overlap },
+    Scene { name: "mixed_sheets", setup: mixed_sheets },
+    Scene { name: "atlas", setup: atlas },
+];
+
+fn keep_first(game: &mut GameState, count: usize) {
+    let rest = game.world.entities().skip(count).collect::<Vec<_>>();
+    for id in rest {
+        game.despawn(id);
+    }
+}
+
+fn place(game: &mut GameState, id: EntityId, position: cgmath::Vector3<f32>) {
+    let transform = game.world.transforms.get_mut(&id).unwrap();
+    transform.position = position;
+    transform.previous_position = position;
+}
+
+fn set_frame(game: &mut GameState, id: EntityId, frame: usize) {
+    game.world.animations.get_mut(&id).unwrap().animator.current_frame = frame;
+}
+
+// Every entity in a grid, each showing a different sprite frame
+fn frame_grid(game: &mut GameState) {
+    let entities = game.world.entities().collect::<Vec<_>>();
+    for (i, id) in entities.into_iter().enumerate() {
+        place(game, id, cgmath::Vector3::new((i % 5) as f32 * 1.2 - 2.4, (i / 5) as f32 * 1.2 - 1.8, 0.0));
+        set_frame(game, id, i);
+    }
+}
+
+// Partially overlapping sprites to catch depth ordering and blending changes
+fn overlap(game: &mut GameState) {
+    keep_first(game, 3);
+    let entities = game.world.entities().collect::<Vec<_>>();
+    for (i, id) in entities.into_iter().enumerate() {
+        place(game, id, cgmath::Vector3::new(i as f32 * 0.4 - 0.4, i as f32 * 0.2 - 0.2, 0.0));
+        set_frame(game, id, i * 6);
+    }
+}
+
+// Alternating sprites from two sheets, drawn as separate batches. The trees
+// are static sprites without an animation.
+fn mixed_sheets(game: &mut GameState) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/happy_tree.ron");
+    let tree = game.add_sprite_sheet(SpriteSheet::load(path).unwrap());
+
+    keep_first(game, 6);
+    let entities = game.world.entities().collect::<Vec<_>>();
+    for (i, id) in entities.into_iter().enumerate() {
+        place(game, id, cgmath::Vector3::new(i as f32 - 2.5, 0.0, 0.0));
+        if i % 2 == 1 {
+            game.world.animations.remove(&id);
+            let sprite = game.world.sprites.get_mut(&id).unwrap();
+            sprite.sprite_sheet = tree;
+            sprite.frame = 0;
+        } else {
+            set_frame(game, id, i);
+        }
+    }
+}
+
+// The mixed_sheets scene packed into one atlas, drawn as a single batch
+fn atlas(game: &mut GameState) {
+    mixed_sheets(game);
+    game.pack_sprite_sheets(1024).unwrap();
+}
+
+pub struct Comparison {
+    pub mismatched_pixels: u32,
+    pub diff: image::RgbaImage,
+}
+
+// Pixels within tolerance are drawn as dimmed greyscale of the expected
+// image, mismatched pixels in red
+pub fn compare(actual: &image::RgbaImage, expected: &image::RgbaImage, tolerance: u8) -> Result<Comparison> {
+    ensure!(
+        actual.dimensions() == expected.dimensions(),
+        "Size mismatch: got {:?}, expected {:?}",
+        actual.dimensions(),
+        expected.dimensions()
+    );
+
+    let mut mismatched_pixels = 0;
+    let mut diff = image::RgbaImage::new(actual.width(), actual.height());
+
+    for ((a, e), d) in actual.pixels().zip(expected.pixels()).zip(diff.pixels_mut()) {
+        let max_delta = a.0.iter()
+            .zip(e.0.iter())
+            .map(|(a, e)| (*a as i16 - *e as i16).abs() as u8)
+            .max()
+            .unwrap_or(0);
+
+        if max_delta > tolerance {
+            mismatched_pixels += 1;
+            *d = image::Rgba([255, 0, 0, 255]);
+        } else {
+            let grey = ((e.0[0] as u32 + e.0[1] as u32 + e.0[2] as u32) / 3 / 4) as u8;
+            *d = image::Rgba([grey, grey, grey, 255]);
+        }
+    }
+
+    Ok(Comparison { mismatched_pixels, diff })
+}
+
+fn new_game() -> Result<GameState> {
+    let clock = ManualClock::new();
+    GameState::new(&clock, 0, SpriteSheet::load(SpriteSheet::default_path())?)
+}
+
+// Every scene gets its own renderer, scenes may replace the sheets of the game
+async fn render_scene(scene: &Scene) -> Result<image::RgbaImage> {
+    let mut game = new_game()?;
+    (scene.setup)(&mut game);
+
+    let mut headless = HeadlessState::new(WIDTH, HEIGHT, &game).await?;
+    headless.update(&game, 1.0);
+    headless.render().await
+}
+
+fn reference_path(scene: &Scene) -> PathBuf {
+    Path::new(REFERENCE_DIR).join(format!("{}.png", scene.name))
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Mode {
+    // Compares every scene, a missing reference is a failure
+    Check,
+    // Compares the scenes that have a reference and skips the rest, for
+    // cargo test on checkouts where the references haven't been rendered
+    CheckExisting,
+    // Rewrites the references
+    Update,
+}
+
+/// Renders every scene and compares it against its reference image, or
+/// rewrites the references with Mode::Update
+pub async fn run(mode: Mode) -> Result<()> {
+    let mut failures = Vec::new();
+
+    for scene in SCENES {
+        let reference = reference_path(scene);
+        if mode == Mode::CheckExisting && !reference.exists() {
+            println!(
+                "{}: skipped, no reference at {}. Render it with `cargo run -- --golden update`",
+                scene.name,
+                reference.display()
+            );
+            continue;
+        }
+
+        let actual = render_scene(scene).await?;
+
+        if mode == Mode::Update {
+            std::fs::create_dir_all(REFERENCE_DIR)?;
+            actual.save(&reference)
+                .with_context(|| format!("Failed to write {}", reference.display()))?;
+            println!("{}: updated", scene.name);
+            continue;
+        }
+
+        let expected = image::open(&reference)
+            .with_context(|| format!(
+                "Failed to open reference {}, render it with `cargo run -- --golden update`",
+                reference.display()
+            ))?
+            .to_rgba8();
+        let comparison = compare(&actual, &expected, TOLERANCE)
+            .with_context(|| format!("Scene {}", scene.name))?;
+
+        if comparison.mismatched_pixels == 0 {
+            println!("{}: ok", scene.name);
+        } else {
+            std::fs::create_dir_all(OUTPUT_DIR)?;
+            let output = Path::new(OUTPUT_DIR);
+            actual.save(output.join(format!("{}.actual.png", scene.name)))?;
+            comparison.diff.save(output.join(format!("{}.diff.png", scene.name)))?;
+            println!("{}: {} pixels differ, see {}", scene.name, comparison.mismatched_pixels, output.display());
+            failures.push(scene.name);
+        }
+    }
+
+    if !failures.is_empty() {
+        bail!("Golden image mismatch in: {}", failures.join(", "));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(pixels: &[[u8; 4]]) -> image::RgbaImage {
+        let mut image = image::RgbaImage::new(pixels.len() as u32, 1);
+        for (x, pixel) in pixels.iter().enumerate() {
+            image.put_pixel(x as u32, 0, image::Rgba(*pixel));
+        }
+        image
+    }
+
+    #[test]
+    fn compare_counts_pixels_past_tolerance() {
+        let expected = row(&[[100, 100, 100, 255], [100, 100, 100, 255], [100, 100, 100, 255]]);
+        let actual = row(&[[100, 100, 100, 255], [102, 98, 100, 255], [100, 100, 103, 255]]);
+
+        let comparison = compare(&actual, &expected, 2).unwrap();
+        assert_eq!(comparison.mismatched_pixels, 1);
+        assert_eq!(*comparison.diff.get_pixel(2, 0), image::Rgba([255, 0, 0, 255]));
+        assert_ne!(*comparison.diff.get_pixel(1, 0), image::Rgba([255, 0, 0, 255]));
+
+        assert_eq!(compare(&actual, &expected, 3).unwrap().mismatched_pixels, 0);
+        assert_eq!(compare(&actual, &expected, 0).unwrap().mismatched_pixels, 2);
+    }
+
+    #[test]
+    fn compare_rejects_size_mismatch() {
+        let expected = row(&[[0, 0, 0, 255]; 2]);
+        let actual = row(&[[0, 0, 0, 255]; 3]);
+        assert!(compare(&actual, &expected, TOLERANCE).is_err());
+    }
+
+    // Needs a graphics adapter, skipped on machines without one. Scenes
+    // without a checked-in reference are skipped too.
+    #[test]
+    fn golden_images_match() {
+        let instance = wgpu::Instance::new(wgpu::BackendBit::PRIMARY);
+        let adapter = futures::executor::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::LowPower,
+            compatible_surface: None,
+        }));
+        if adapter.is_none() {
+            eprintln!("No graphics adapter, skipping golden images");
+            return;
+        }
+
+        futures::executor::block_on(run(Mode::CheckExisting)).unwrap();
+    }
+}
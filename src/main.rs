@@ -5,31 +5,132 @@ mod game;
 mod texture;
 mod camera;
 mod controller;
+mod clock;
+mod timestep;
+mod golden;
+mod sprite_sheet;
+mod assets;
 
-use crate::rendering::State;
+use crate::assets::AssetWatcher;
+use crate::clock::{ManualClock, RealClock};
+use crate::rendering::{SheetResources, State};
+use crate::rendering::headless::HeadlessState;
+use crate::sprite_sheet::{SpriteSheet, SpriteSheetHandle};
+use crate::timestep::{FixedTimestep, FramePacer};
 use winit::{
     event::*,
     event_loop::{EventLoop, ControlFlow},
     window::{WindowBuilder},
 };
 
+// Frames are drawn at this rate, interpolating between simulation ticks
+const FRAME_RATE: u32 = 60;
+
+// Screenshots are taken after simulating this many ticks from the seed
+const SCREENSHOT_TICKS: u32 = 300;
+
+// Set HELLO_WGPU_SEED to replay a previous run
+fn simulation_seed() -> u64 {
+    let seed = std::env::var("HELLO_WGPU_SEED")
+        .ok()
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or_else(rand::random);
+    // Printed even without RUST_LOG, bug reports need it to reproduce a run
+    eprintln!("Simulation seed: {}", seed);
+    seed
+}
+
+// Renders a single frame offscreen and writes it to a PNG, no window needed
+fn screenshot(path: &str) -> anyhow::Result<()> {
+    let mut sim_clock = ManualClock::new();
+    let sprite_sheet = SpriteSheet::load(SpriteSheet::default_path())?;
+    let mut game = game::GameState::new(&sim_clock, simulation_seed(), sprite_sheet)?;
+    let timestep = FixedTimestep::new(100, 5, &sim_clock);
+
+    for _ in 0..SCREENSHOT_TICKS {
+        sim_clock.advance(timestep.tick_duration);
+        game.update(&sim_clock);
+    }
+
+    futures::executor::block_on(async {
+        let mut headless = HeadlessState::new(1280, 720, &game).await?;
+        headless.update(&game, 1.0);
+        headless.render_to_file(path).await
+    })
+}
+
+// Reloads a sprite sheet that changed on disk. Everything is loaded before
+// anything is replaced, so a broken file leaves the old sheet in use.
+fn reload_sprite_sheet(game: &mut game::GameState, state: &mut State, handle: SpriteSheetHandle) -> anyhow::Result<()> {
+    use anyhow::Context;
+
+    let path = game.sprite_sheet(handle).path.clone().context("Sprite sheet was not loaded from a file")?;
+    let sheet = SpriteSheet::load(&path)?;
+    let resources = SheetResources::new(&state.device, &state.queue, &state.sprites.texture_bind_group_layout, &sheet)?;
+    game.reload_sprite_sheet(handle, sheet)
+        .with_context(|| format!("Failed to reload {}", path.display()))?;
+    state.sprites.replace_sheet(handle, resources);
+    log::info!("Reloaded {}", path.display());
+    Ok(())
+}
+
+fn exit_with_error(e: anyhow::Error) -> ! {
+    eprintln!("{:?}", e);
+    std::process::exit(1);
+}
+
 fn main() {
     env_logger::init();
+
+    let args: Vec<String> = std::env::args().collect();
+    if let [_, flag, arg] = args.as_slice() {
+        let result = match (flag.as_str(), arg.as_str()) {
+            ("--screenshot", path) => Some(screenshot(path)),
+            ("--golden", "check") => Some(futures::executor::block_on(golden::run(golden::Mode::Check))),
+            ("--golden", "update") => Some(futures::executor::block_on(golden::run(golden::Mode::Update))),
+            _ => None,
+        };
+
+        if let Some(result) = result {
+            if let Err(e) = result {
+                exit_with_error(e);
+            }
+            return;
+        }
+    }
+
     let event_loop = EventLoop::new();
     let window = WindowBuilder::new()
         .with_inner_size(winit::dpi::LogicalSize::new(1280, 720))
         .build(&event_loop)
         .unwrap();
 
-    let mut game = game::GameState::new();
+    // Wall-clock time only decides how many ticks to run, the simulation
+    // itself sees time advancing in exact tick-sized steps
+    let real_clock = RealClock::new();
+    let mut sim_clock = ManualClock::new();
+
+    let sprite_sheet = SpriteSheet::load(SpriteSheet::default_path()).unwrap_or_else(|e| exit_with_error(e));
+    let mut game = game::GameState::new(&sim_clock, simulation_seed(), sprite_sheet).unwrap_or_else(|e| exit_with_error(e));
+    let mut timestep = FixedTimestep::new(100, 5, &real_clock);
+    let mut frames = FramePacer::new(FRAME_RATE, &real_clock);
+    let mut assets = AssetWatcher::new(std::time::Duration::from_millis(500), &real_clock);
+    #[cfg(feature = "shader-hot-reload")]
+    let mut shaders = rendering::shader_reload::ShaderReloader::new(std::time::Duration::from_millis(500), &real_clock)
+        .unwrap_or_else(|e| exit_with_error(e));
 
     // Since main can't be async, we're going to need to block
-    let mut state = futures::executor::block_on(State::new(&window, &game));
+    let mut state = futures::executor::block_on(State::new(&window, &game)).unwrap_or_else(|e| exit_with_error(e));
+    game.camera.resize(state.size.width, state.size.height);
+
+    // Set HELLO_WGPU_GPU_CROWD=1 to move the crowd with a compute shader
+    if std::env::var("HELLO_WGPU_GPU_CROWD").map_or(false, |v| v == "1") {
+        state.enable_gpu_crowd(&game).unwrap_or_else(|e| exit_with_error(e));
+        game.move_ai = false;
+    }
     
 
     event_loop.run(move |event, _, control_flow| {
-
-        //*control_flow = ControlFlow::WaitUntil(std::time::Instant::now() + std::time::Duration::from_millis(5));
         match event {
             Event::WindowEvent {
                 ref event,
@@ -50,10 +151,14 @@ fn main() {
                             _ => {}
                         }
                     },
-                    WindowEvent::Resized(physical_size) => state.resize(*physical_size),
+                    WindowEvent::Resized(physical_size) => {
+                        state.resize(*physical_size);
+                        game.camera.resize(physical_size.width, physical_size.height);
+                    },
                     WindowEvent::ScaleFactorChanged { new_inner_size, .. } => { 
                         // new_inner_size is &&mut so we have to dereference it twice
                         state.resize(**new_inner_size);
+                        game.camera.resize(new_inner_size.width, new_inner_size.height);
                     },
                     _ => {}
                 }
@@ -73,14 +178,53 @@ fn main() {
                 }
             },
             Event::MainEventsCleared => {
-                // RedrawRequested will only trigger once, unless we manually
-                // request it.
+                // Input wakes the loop up as well, a frame is only done once
+                // it's due
+                if frames.frame_due(&real_clock) {
+                    let steps = timestep.advance(&real_clock);
+                    for _ in 0..steps {
+                        sim_clock.advance(timestep.tick_duration);
+                        game.update(&sim_clock);
+                        if let Some(crowd) = &mut state.crowd {
+                            if let Err(e) = crowd.tick(&state.device, &state.queue, &state.sprites, &game, timestep.tick_duration) {
+                                eprintln!("{:?}", e);
+                            }
+                        }
+                    }
+                    game.time_delta = Some(timestep.frame_time);
+
+                    for handle in assets.poll(&real_clock, &game.sprite_sheets) {
+                        match reload_sprite_sheet(&mut game, &mut state, handle) {
+                            Ok(()) => assets.rewatch(handle, game.sprite_sheet(handle)),
+                            // The previous sheet stays in use
+                            Err(e) => {
+                                eprintln!("{:?}", e);
+                                state.sprites.sheet_errors.insert(handle, format!("{:?}", e));
+                            }
+                        }
+                    }
 
-                if game.last_frame.elapsed() > std::time::Duration::from_millis(10) {
-                    game.update();
-                    state.update(&game);
+                    #[cfg(feature = "shader-hot-reload")]
+                    match shaders.poll(&real_clock) {
+                        Some(Ok((vs, fs))) => match state.sprites.set_shaders(&state.device, &vs, &fs) {
+                            Ok(()) => {
+                                state.shader_error = None;
+                                log::info!("Reloaded shaders");
+                            }
+                            Err(e) => state.shader_error = Some(format!("{:?}", e)),
+                        },
+                        Some(Err(e)) => state.shader_error = Some(format!("{:?}", e)),
+                        None => {}
+                    }
+
+                    // Drawn every frame, also between ticks, with positions
+                    // interpolated between the last two ticks
+                    state.update(&game, timestep.alpha());
                     window.request_redraw();
                 }
+                if *control_flow != ControlFlow::Exit {
+                    *control_flow = ControlFlow::WaitUntil(std::time::Instant::now() + frames.until_next_frame(&real_clock));
+                }
             },
             _ => {}
         }
@@ -1,7 +1,17 @@
 extern crate imgui_winit_support;
 
+pub mod crowd;
+pub mod headless;
+mod shader_interface;
+#[cfg(feature = "shader-hot-reload")]
+pub mod shader_reload;
+
 use crate::texture;
-use crate::game::GameState;
+use crate::game::{Animated, EntityId, GameState, Sprite, Transform};
+use crate::sprite_sheet::{Frame, SpriteSheet, SpriteSheetHandle};
+
+use std::collections::BTreeMap;
+use std::ops::Range;
 
 use imgui::*;
 use imgui_wgpu::{Renderer, RendererConfig};
@@ -25,60 +35,154 @@ struct Vertex {
 #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct InstanceRaw {
     model: [[f32; 4]; 4],
-    frame: usize,
+    frame: u32,
+    flags: u32,
+    tint: [f32; 4],
+    // Scale x, scale y, rotation in radians, opacity. Kept out of the model
+    // matrix since the shader takes the depth from it.
+    transform: [f32; 4],
 }
 
+// Bits of InstanceRaw::flags, have to match shader.vert
+const INSTANCE_FLIP_X: u32 = 1;
+const INSTANCE_FLIP_Y: u32 = 2;
+
 impl InstanceRaw {
-    fn from_instance(instance: &crate::game::Instance) -> InstanceRaw {
+    // alpha interpolates between the previous and the current simulation tick
+    fn from_components(transform: &Transform, sprite: &Sprite, animated: Option<&Animated>, alpha: f32) -> InstanceRaw {
+        use cgmath::VectorSpace;
+        let position = transform.previous_position.lerp(transform.position, alpha);
+
+        // A mirrored clip flipped by the sprite ends up facing the original way
+        let mirrored = animated.map_or(false, |a| a.state.is_mirrored());
+        let mut flags = 0;
+        if sprite.flip_x != mirrored {
+            flags |= INSTANCE_FLIP_X;
+        }
+        if sprite.flip_y {
+            flags |= INSTANCE_FLIP_Y;
+        }
+
         InstanceRaw {
-            model: (cgmath::Matrix4::from_translation(instance.position)).into(),
-            frame: instance.animator.current_frame,
+            model: (cgmath::Matrix4::from_translation(position)).into(),
+            frame: animated.map_or(sprite.frame, |a| a.animator.current_frame) as u32,
+            flags,
+            tint: sprite.tint,
+            transform: [transform.scale.x, transform.scale.y, transform.rotation.0, sprite.opacity],
         }
     }
 
+    const ATTRIBUTES: &'static [wgpu::VertexAttributeDescriptor] = &[
+        wgpu::VertexAttributeDescriptor {
+            offset: 0,
+            // While our vertex shader only uses locations 0, and 1 now, in later tutorials we'll
+            // be using 2, 3, and 4, for Vertex. We'll start at slot 5 not conflict with them later
+            shader_location: 5,
+            format: wgpu::VertexFormat::Float4,
+        },
+        // A mat4 takes up 4 vertex slots as it is technically 4 vec4s. We need to define a slot
+        // for each vec4. We don't have to do this in code though.
+        wgpu::VertexAttributeDescriptor {
+            offset: std::mem::size_of::<[f32; 4]>() as wgpu::BufferAddress,
+            shader_location: 6,
+            format: wgpu::VertexFormat::Float4,
+        },
+        wgpu::VertexAttributeDescriptor {
+            offset: std::mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
+            shader_location: 7,
+            format: wgpu::VertexFormat::Float4,
+        },
+        wgpu::VertexAttributeDescriptor {
+            offset: std::mem::size_of::<[f32; 12]>() as wgpu::BufferAddress,
+            shader_location: 8,
+            format: wgpu::VertexFormat::Float4,
+        },
+        wgpu::VertexAttributeDescriptor {
+            offset: std::mem::size_of::<[f32; 16]>() as wgpu::BufferAddress,
+            shader_location: 9,
+            format: wgpu::VertexFormat::Uint,
+        },
+        wgpu::VertexAttributeDescriptor {
+            offset: (std::mem::size_of::<[f32; 16]>() + std::mem::size_of::<u32>()) as wgpu::BufferAddress,
+            shader_location: 10,
+            format: wgpu::VertexFormat::Uint,
+        },
+        wgpu::VertexAttributeDescriptor {
+            offset: (std::mem::size_of::<[f32; 16]>() + std::mem::size_of::<[u32; 2]>()) as wgpu::BufferAddress,
+            shader_location: 11,
+            format: wgpu::VertexFormat::Float4,
+        },
+        wgpu::VertexAttributeDescriptor {
+            offset: (std::mem::size_of::<[f32; 20]>() + std::mem::size_of::<[u32; 2]>()) as wgpu::BufferAddress,
+            shader_location: 12,
+            format: wgpu::VertexFormat::Float4,
+        },
+    ];
+
     fn desc<'a>() -> wgpu::VertexBufferDescriptor<'a> {
-        use std::mem;
         wgpu::VertexBufferDescriptor {
-            stride: mem::size_of::<InstanceRaw>() as wgpu::BufferAddress,
+            stride: std::mem::size_of::<InstanceRaw>() as wgpu::BufferAddress,
             // We need to switch from using a step mode of Vertex to Instance
             // This means that our shaders will only change to use the next
             // instance when the shader starts processing a new instance
             step_mode: wgpu::InputStepMode::Instance,
-            attributes: &[
-                wgpu::VertexAttributeDescriptor {
-                    offset: 0,
-                    // While our vertex shader only uses locations 0, and 1 now, in later tutorials we'll
-                    // be using 2, 3, and 4, for Vertex. We'll start at slot 5 not conflict with them later
-                    shader_location: 5,
-                    format: wgpu::VertexFormat::Float4,
-                },
-                // A mat4 takes up 4 vertex slots as it is technically 4 vec4s. We need to define a slot
-                // for each vec4. We don't have to do this in code though.
-                wgpu::VertexAttributeDescriptor {
-                    offset: mem::size_of::<[f32; 4]>() as wgpu::BufferAddress,
-                    shader_location: 6,
-                    format: wgpu::VertexFormat::Float4,
-                },
-                wgpu::VertexAttributeDescriptor {
-                    offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
-                    shader_location: 7,
-                    format: wgpu::VertexFormat::Float4,
-                },
-                wgpu::VertexAttributeDescriptor {
-                    offset: mem::size_of::<[f32; 12]>() as wgpu::BufferAddress,
-                    shader_location: 8,
-                    format: wgpu::VertexFormat::Float4,
-                },
-                wgpu::VertexAttributeDescriptor {
-                    offset: mem::size_of::<[f32; 16]>() as wgpu::BufferAddress,
-                    shader_location: 9,
-                    format: wgpu::VertexFormat::Uint,
-                },
-            ],
+            attributes: Self::ATTRIBUTES,
         }
     }
 }
 
+// Has to match the array sizes in shader.vert
+pub const MAX_SPRITE_FRAMES: usize = 64;
+
+// Instances the instance buffer has room for before it has to grow
+const INITIAL_INSTANCE_CAPACITY: usize = 64;
+
+// Fails to compile when shader.vert reads a vertex input the buffers don't
+// provide in the same format
+const _: [(); 1] = [(); shader_interface::vertex_inputs_match(
+    shader_interface::shader_vert::INPUTS,
+    &[Vertex::ATTRIBUTES, InstanceRaw::ATTRIBUTES],
+) as usize];
+
+// The uniform structs have to be exactly as large as the blocks in
+// shader.vert, otherwise these fail to compile with mismatched array sizes
+const _: [(); shader_interface::shader_vert::UNIFORMS_SIZE] = [(); std::mem::size_of::<Uniforms>()];
+const _: [(); shader_interface::shader_vert::SPRITE_SHEET_SIZE] = [(); std::mem::size_of::<SheetUniforms>()];
+
+// What the sprite pipeline layout provides, reloaded shaders are checked
+// against it before they replace the pipeline
+#[cfg(feature = "shader-hot-reload")]
+const SPRITE_BINDINGS: &[shader_interface::Binding] = &[
+    shader_interface::Binding {
+        set: 0,
+        binding: 0,
+        ty: spirv_reflect::types::ReflectDescriptorType::SampledImage,
+        visibility: wgpu::ShaderStage::FRAGMENT,
+        size: None,
+    },
+    shader_interface::Binding {
+        set: 0,
+        binding: 1,
+        ty: spirv_reflect::types::ReflectDescriptorType::Sampler,
+        visibility: wgpu::ShaderStage::FRAGMENT,
+        size: None,
+    },
+    shader_interface::Binding {
+        set: 0,
+        binding: 2,
+        ty: spirv_reflect::types::ReflectDescriptorType::UniformBuffer,
+        visibility: wgpu::ShaderStage::VERTEX,
+        size: Some(std::mem::size_of::<SheetUniforms>()),
+    },
+    shader_interface::Binding {
+        set: 1,
+        binding: 0,
+        ty: spirv_reflect::types::ReflectDescriptorType::UniformBuffer,
+        visibility: wgpu::ShaderStage::VERTEX,
+        size: Some(std::mem::size_of::<Uniforms>()),
+    },
+];
+
 #[repr(C)]
 // This is so we can store this in a buffer
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
@@ -86,21 +190,13 @@ pub struct Uniforms {
     // We can't use cgmath with bytemuck directly so we'll have
     // to convert the Matrix4 into a 4x4 f32 array
     view_proj: [[f32; 4]; 4],
-    sprite_rects: [[f32; 4]; 24],
 }
 
 impl Uniforms {
     fn new() -> Self {
-        let mut sprite_rects: [[f32; 4]; 24] = [[0.0, 0.0, 0.0, 0.0]; 24];
-
-        for i in 0..24 {
-            sprite_rects[i] = [(i % 6) as f32 / 6.0, (i / 6) as f32 / 4.0, ((i % 6) + 1) as f32 / 6.0, ((i / 6) + 1) as f32 / 4.0];
-        }
-
         use cgmath::SquareMatrix;
         Self {
             view_proj: cgmath::Matrix4::identity().into(),
-            sprite_rects,
         }
     }
 
@@ -109,6 +205,32 @@ impl Uniforms {
     }
 }
 
+// Frames of one sprite sheet, bound together with its texture
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct SheetUniforms {
+    sprite_rects: [[f32; 4]; MAX_SPRITE_FRAMES],
+    // Only xy are used, arrays in uniform blocks are padded to vec4 anyway
+    sprite_pivots: [[f32; 4]; MAX_SPRITE_FRAMES],
+}
+
+impl SheetUniforms {
+    fn new(frames: &[Frame]) -> Self {
+        let mut sprite_rects = [[0.0; 4]; MAX_SPRITE_FRAMES];
+        let mut sprite_pivots = [[0.5, 0.5, 0.0, 0.0]; MAX_SPRITE_FRAMES];
+
+        for (i, frame) in frames.iter().take(MAX_SPRITE_FRAMES).enumerate() {
+            sprite_rects[i] = frame.rect;
+            sprite_pivots[i] = [frame.pivot[0], frame.pivot[1], 0.0, 0.0];
+        }
+
+        Self {
+            sprite_rects,
+            sprite_pivots,
+        }
+    }
+}
+
 /**
  * Could be created also with the following but needs <'static>
  * wgpu::VertexBufferDescriptor {
@@ -117,17 +239,19 @@ impl Uniforms {
     attributes: &wgpu::vertex_attr_array![0 => Float3, 1 => Float3, ...],
 } */
 impl Vertex {
+    const ATTRIBUTES: &'static [wgpu::VertexAttributeDescriptor] = &[
+        wgpu::VertexAttributeDescriptor {
+            offset: 0,
+            shader_location: 0,
+            format: wgpu::VertexFormat::Float3,
+        },
+    ];
+
     fn desc<'a>() -> wgpu::VertexBufferDescriptor<'a> {
         wgpu::VertexBufferDescriptor {
             stride: std::mem::size_of::<Vertex>() as wgpu::BufferAddress,
             step_mode: wgpu::InputStepMode::Vertex,
-            attributes: &[
-                wgpu::VertexAttributeDescriptor {
-                    offset: 0,
-                    shader_location: 0,
-                    format: wgpu::VertexFormat::Float3,
-                },
-            ]
+            attributes: Self::ATTRIBUTES,
         }
     }
 }
@@ -152,59 +276,87 @@ pub struct ImguiState {
     demo_open: bool,
 }
 
-pub struct State {
-    pub surface: wgpu::Surface,
-    pub device: wgpu::Device,
-    pub queue: wgpu::Queue,
-    pub sc_desc: wgpu::SwapChainDescriptor,
-    pub swap_chain: wgpu::SwapChain,
-    pub size: winit::dpi::PhysicalSize<u32>,
-    pub pointer: (f64, f64),
+/// GPU side of a sprite sheet: its texture and frame rectangles
+pub struct SheetResources {
+    pub texture: texture::Texture,
+    pub uniform_buffer: wgpu::Buffer,
+    pub bind_group: wgpu::BindGroup,
+}
+
+impl SheetResources {
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, layout: &wgpu::BindGroupLayout, sheet: &SpriteSheet) -> anyhow::Result<Self> {
+        let texture = match &sheet.image {
+            Some(image) => {
+                let image = image::DynamicImage::ImageRgba8(image.clone());
+                texture::Texture::from_image(device, queue, &image, Some("sprite_sheet"), &sheet.texture_options)?
+            }
+            None => texture::Texture::from_path(device, queue, &sheet.image_path, &sheet.texture_options)?,
+        };
+
+        let uniform_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Sheet Uniform Buffer"),
+                contents: bytemuck::cast_slice(&[SheetUniforms::new(&sheet.frames)]),
+                usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            }
+        );
+
+        let bind_group = device.create_bind_group(
+            &wgpu::BindGroupDescriptor {
+                layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&texture.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&texture.sampler),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::Buffer(uniform_buffer.slice(..)),
+                    },
+                ],
+                label: Some("sheet_bind_group"),
+            }
+        );
+
+        Ok(Self { texture, uniform_buffer, bind_group })
+    }
+}
+
+/// The sprite pipeline and its resources. Independent of where the output
+/// goes so it can be shared by the windowed and the headless renderer.
+pub struct SpriteRenderer {
     pub render_pipeline: wgpu::RenderPipeline,
-    pub draw_challenge: bool,
+    // Kept for rebuilding the pipeline with new shaders
+    #[cfg(feature = "shader-hot-reload")]
+    render_pipeline_layout: wgpu::PipelineLayout,
+    #[cfg(feature = "shader-hot-reload")]
+    format: wgpu::TextureFormat,
     pub vertex_buffer: wgpu::Buffer,
-    pub index_buffer: wgpu::Buffer, 
+    pub index_buffer: wgpu::Buffer,
     pub num_indices: u32,
-    pub diffuse_texture: texture::Texture,
-    pub diffuse_bind_group: wgpu::BindGroup,
+    pub texture_bind_group_layout: wgpu::BindGroupLayout,
+    // Indexed by SpriteSheetHandle, None for sheets that failed to upload
+    pub sheets: Vec<Option<SheetResources>>,
+    // GameState::sheets_generation of the uploaded sheets
+    sheets_generation: u32,
+    // Shown in the overlay until the sheet uploads or reloads
+    pub sheet_errors: BTreeMap<SpriteSheetHandle, String>,
     pub uniforms: Uniforms,
     pub uniform_buffer: wgpu::Buffer,
     pub uniform_bind_group: wgpu::BindGroup,
-    pub imgui: ImguiState,
-    pub bg_color: [f32; 3],
     pub instance_buffer: wgpu::Buffer,
-    pub depth_texture: texture::Texture,
+    // In instances, doubled whenever the scene outgrows the buffer
+    pub instance_capacity: usize,
+    // Instances are sorted by sprite sheet, one draw call per sheet
+    batches: Vec<(SpriteSheetHandle, Range<u32>)>,
 }
 
-impl State {
-    // Creating some of the wgpu types requires async code
-    pub async fn new(window: &Window, game: &GameState) -> Self {
-        let size = window.inner_size();
-
-        // The instance is a handle to our GPU
-        // BackendBit::PRIMARY => Vulkan + Metal + DX12 + Browser WebGPU
-        let instance = wgpu::Instance::new(wgpu::BackendBit::PRIMARY);
-        let surface = unsafe { instance.create_surface(window) };
-        let adapter = instance
-            .request_adapter(&wgpu::RequestAdapterOptions {
-                power_preference: wgpu::PowerPreference::HighPerformance,
-                compatible_surface: Some(&surface),
-            })
-            .await
-            .unwrap();
-
-        let (device, queue) = adapter
-            .request_device(
-                &wgpu::DeviceDescriptor {
-                    features: wgpu::Features::empty(),
-                    limits: wgpu::Limits::default(),
-                    shader_validation: true,
-                },
-                None, // Trace path
-            )
-            .await
-            .unwrap();
-        
+impl SpriteRenderer {
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, format: wgpu::TextureFormat, game: &GameState) -> anyhow::Result<Self> {
         let vertex_buffer = device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
                 label: Some("Vertex Buffer"),
@@ -223,21 +375,6 @@ impl State {
 
         let num_indices = INDICES.len() as u32;
 
-        // Describes how images are displayed to Surface
-        let sc_desc = wgpu::SwapChainDescriptor {
-            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT,
-            format: wgpu::TextureFormat::Bgra8UnormSrgb, // The screen format that is most widely available, should use the screens native format but there's no way to query it yet
-            width: size.width,
-            height: size.height,
-            present_mode: wgpu::PresentMode::Immediate,
-        };
-        let swap_chain = device.create_swap_chain(&surface, &sc_desc);
-
-        // 6x4 sprites in 600x400 pixels
-        let diffuse_bytes = include_bytes!("trump_run.png");
-        let diffuse_texture = texture::Texture::from_bytes(&device, &queue, diffuse_bytes, "trump_run").unwrap();
-        let depth_texture = texture::Texture::create_depth_texture(&device, &sc_desc, "depth_texture");
-
         let texture_bind_group_layout = device.create_bind_group_layout(
             &wgpu::BindGroupLayoutDescriptor {
                 entries: &[
@@ -259,72 +396,28 @@ impl State {
                         },
                         count: None,
                     },
-                ],
-                label: Some("texture_bind_group_layout"),
-            }
-        );
-
-        let diffuse_bind_group = device.create_bind_group(
-            &wgpu::BindGroupDescriptor {
-                layout: &texture_bind_group_layout,
-                entries: &[
-                    wgpu::BindGroupEntry {
-                        binding: 0,
-                        resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStage::VERTEX,
+                        ty: wgpu::BindingType::UniformBuffer {
+                            dynamic: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
                     },
-                    wgpu::BindGroupEntry {
-                        binding: 1,
-                        resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
-                    }
                 ],
-                label: Some("diffuse_bind_group"),
+                label: Some("texture_bind_group_layout"),
             }
         );
 
-        // Set up dear imgui
-        let imgui = {
-            let mut imgui = imgui::Context::create();
-
-            let mut platform = imgui_winit_support::WinitPlatform::init(&mut imgui);
-            platform.attach_window(
-                imgui.io_mut(),
-                &window,
-                imgui_winit_support::HiDpiMode::Default,
-            );
-            imgui.set_ini_filename(None);
-
-            imgui.io_mut().mouse_pos = [0.0, 0.0];
-
-            imgui.style_mut().window_border_size = 0.0;
-            imgui.style_mut().window_padding = [10.0, 10.0];
-
-            let hidpi_factor = window.scale_factor();
-
-            let font_size = (16.0 * hidpi_factor) as f32;
-            imgui.io_mut().font_global_scale = (1.0 / hidpi_factor) as f32;
-
-            imgui.fonts().add_font(&[FontSource::DefaultFontData {
-                config: Some(imgui::FontConfig {
-                    oversample_h: 1,
-                    pixel_snap_h: true,
-                    size_pixels: font_size,
-                    ..Default::default()
-                }),
-            }]);
-
-            let renderer_config = RendererConfig {
-                texture_format: sc_desc.format,
-                ..Default::default()
-            };
-
-            let imgui_renderer = Renderer::new(&mut imgui, &device, &queue, renderer_config);
-
-            ImguiState{ctx: imgui, renderer: imgui_renderer, platform, demo_open: false}
-        };
+        let sheets = game.sprite_sheets
+            .iter()
+            .map(|sheet| SheetResources::new(device, queue, &texture_bind_group_layout, sheet).map(Some))
+            .collect::<anyhow::Result<_>>()?;
 
         let mut uniforms = Uniforms::new();
 
-        uniforms.update_view_proj(game.camera.build_view_projection_matrix().into());
+        uniforms.update_view_proj(game.camera.build_view_projection_matrix(1.0).into());
 
         let uniform_buffer = device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
@@ -360,18 +453,18 @@ impl State {
             label: Some("uniform_bind_group"),
         });
 
-        let instance_data = game.instances.iter().map(InstanceRaw::from_instance).collect::<Vec<_>>();
+        let (instance_data, batches) = Self::batch_instances(game, 1.0);
 
-        let instance_buffer = device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("Instance Buffer"),
-                contents: bytemuck::cast_slice(&instance_data),
-                usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
-            }
-        );
+        let instance_capacity = instance_data.len().max(INITIAL_INSTANCE_CAPACITY);
+        let instance_buffer = Self::create_instance_buffer(device, instance_capacity);
+        if !instance_data.is_empty() {
+            queue.write_buffer(&instance_buffer, 0, bytemuck::cast_slice(&instance_data));
+        }
 
         let vs_module = device.create_shader_module(wgpu::include_spirv!("shader.vert.spv"));
-        let fs_module = device.create_shader_module(wgpu::include_spirv!("shader.frag.spv"));
+        // Transparent pixels are discarded so they don't write depth, the
+        // shader reloader compiles the same permutation
+        let fs_module = device.create_shader_module(wgpu::include_spirv!("shader.alpha_test.frag.spv"));
 
         let render_pipeline_layout = device.create_pipeline_layout(
             &wgpu::PipelineLayoutDescriptor {
@@ -384,15 +477,46 @@ impl State {
             }
         );
 
-        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        let render_pipeline = Self::create_pipeline(device, &render_pipeline_layout, format, &vs_module, &fs_module);
+
+        Ok(Self {
+            render_pipeline,
+            #[cfg(feature = "shader-hot-reload")]
+            render_pipeline_layout,
+            #[cfg(feature = "shader-hot-reload")]
+            format,
+            vertex_buffer,
+            index_buffer,
+            num_indices,
+            texture_bind_group_layout,
+            sheets,
+            sheets_generation: game.sheets_generation,
+            sheet_errors: BTreeMap::new(),
+            uniforms,
+            uniform_buffer,
+            uniform_bind_group,
+            instance_buffer,
+            instance_capacity,
+            batches,
+        })
+    }
+
+    fn create_pipeline(
+        device: &wgpu::Device,
+        layout: &wgpu::PipelineLayout,
+        format: wgpu::TextureFormat,
+        vs_module: &wgpu::ShaderModule,
+        fs_module: &wgpu::ShaderModule,
+    ) -> wgpu::RenderPipeline {
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
             label: Some("Render Pipeline"),
-            layout: Some(&render_pipeline_layout),
+            layout: Some(layout),
             vertex_stage: wgpu::ProgrammableStageDescriptor {
-                module: &vs_module,
+                module: vs_module,
                 entry_point: "main",
             },
             fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
-                module: &fs_module,
+                module: fs_module,
                 entry_point: "main",
             }),
             rasterization_state: Some(wgpu::RasterizationStateDescriptor {
@@ -404,7 +528,7 @@ impl State {
                 clamp_depth: false,
             }),
             color_states: &[wgpu::ColorStateDescriptor {
-                format: sc_desc.format,
+                format,
                 color_blend: wgpu::BlendDescriptor {
                     src_factor: wgpu::BlendFactor::SrcAlpha,
                     dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
@@ -431,9 +555,264 @@ impl State {
             sample_count: 1,
             sample_mask: !0,
             alpha_to_coverage_enabled: false,
-        });
+        })
+    }
 
-        Self {
+    // Rebuilds the pipeline from shaders compiled at runtime. Shaders that
+    // don't fit the vertex buffers or the layout are rejected and the old
+    // pipeline stays in use.
+    #[cfg(feature = "shader-hot-reload")]
+    pub fn set_shaders(&mut self, device: &wgpu::Device, vs_spirv: &[u32], fs_spirv: &[u32]) -> anyhow::Result<()> {
+        use anyhow::Context;
+
+        let vs = shader_interface::Reflected::new(vs_spirv).context("Failed to reflect shader.vert")?;
+        let fs = shader_interface::Reflected::new(fs_spirv).context("Failed to reflect shader.frag")?;
+        vs.check_vertex_inputs("shader.vert", &[Vertex::desc(), InstanceRaw::desc()])?;
+        vs.check_bindings("shader.vert", wgpu::ShaderStage::VERTEX, SPRITE_BINDINGS)?;
+        fs.check_bindings("shader.frag", wgpu::ShaderStage::FRAGMENT, SPRITE_BINDINGS)?;
+
+        let vs_module = device.create_shader_module(wgpu::ShaderModuleSource::SpirV(vs_spirv.into()));
+        let fs_module = device.create_shader_module(wgpu::ShaderModuleSource::SpirV(fs_spirv.into()));
+        self.render_pipeline = Self::create_pipeline(device, &self.render_pipeline_layout, self.format, &vs_module, &fs_module);
+        Ok(())
+    }
+
+    // Doubles the capacity until the instances fit, never shrinks
+    fn grown_capacity(capacity: usize, instances: usize) -> usize {
+        let mut grown = capacity;
+        if instances > grown {
+            grown = grown.max(1);
+            while grown < instances {
+                grown *= 2;
+            }
+        }
+        grown
+    }
+
+    fn create_instance_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Instance Buffer"),
+            size: (capacity * std::mem::size_of::<InstanceRaw>()) as wgpu::BufferAddress,
+            // Storage for the GPU crowd simulation
+            usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::STORAGE,
+            mapped_at_creation: false,
+        })
+    }
+
+    // Entity drawn at each slot of the instance buffer. Only entities with
+    // both a Sprite and a Transform are drawn.
+    fn instance_order(game: &GameState) -> Vec<EntityId> {
+        let world = &game.world;
+        let mut order = world.sprites
+            .keys()
+            .filter(|id| world.transforms.contains_key(id))
+            .copied()
+            .collect::<Vec<_>>();
+        order.sort_by_key(|id| world.sprites[id].sprite_sheet);
+        order
+    }
+
+    // Instance data sorted by sprite sheet, and the range of instances
+    // using each sheet
+    fn batch_instances(game: &GameState, alpha: f32) -> (Vec<InstanceRaw>, Vec<(SpriteSheetHandle, Range<u32>)>) {
+        let order = Self::instance_order(game);
+
+        let instance_data = order
+            .iter()
+            .map(|id| {
+                let world = &game.world;
+                InstanceRaw::from_components(&world.transforms[id], &world.sprites[id], world.animations.get(id), alpha)
+            })
+            .collect::<Vec<_>>();
+
+        let mut batches: Vec<(SpriteSheetHandle, Range<u32>)> = Vec::new();
+        for (n, id) in order.iter().enumerate() {
+            let sheet = game.world.sprites[id].sprite_sheet;
+            if let Some((handle, range)) = batches.last_mut() {
+                if *handle == sheet {
+                    range.end = n as u32 + 1;
+                    continue;
+                }
+            }
+            batches.push((sheet, n as u32..n as u32 + 1));
+        }
+
+        (instance_data, batches)
+    }
+
+    // Index of the first sheet that still has to be uploaded. Packing replaces
+    // every sheet, so then all of them are uploaded again.
+    fn first_new_sheet(uploaded: usize, uploaded_generation: u32, game: &GameState) -> usize {
+        if uploaded_generation == game.sheets_generation {
+            uploaded.min(game.sprite_sheets.len())
+        } else {
+            0
+        }
+    }
+
+    pub fn update(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, game: &GameState, alpha: f32) {
+        // Upload sheets added to the game since the last update. A failure is
+        // reported once and the sheet isn't drawn until it's reloaded.
+        let first_new = Self::first_new_sheet(self.sheets.len(), self.sheets_generation, game);
+        self.sheets.truncate(first_new);
+        self.sheet_errors.split_off(&SpriteSheetHandle(first_new));
+        self.sheets_generation = game.sheets_generation;
+        for sheet in &game.sprite_sheets[first_new..] {
+            match SheetResources::new(device, queue, &self.texture_bind_group_layout, sheet) {
+                Ok(resources) => self.sheets.push(Some(resources)),
+                Err(e) => {
+                    let error = format!("Failed to load sprite sheet {}: {:?}", sheet.image_path.display(), e);
+                    eprintln!("{}", error);
+                    self.sheet_errors.insert(SpriteSheetHandle(self.sheets.len()), error);
+                    self.sheets.push(None);
+                }
+            }
+        }
+
+        self.uniforms.update_view_proj(game.camera.build_view_projection_matrix(alpha).into());
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[self.uniforms]));
+
+        let (instance_data, batches) = Self::batch_instances(game, alpha);
+        let capacity = Self::grown_capacity(self.instance_capacity, instance_data.len());
+        if capacity != self.instance_capacity {
+            self.instance_buffer = Self::create_instance_buffer(device, capacity);
+            self.instance_capacity = capacity;
+        }
+        if !instance_data.is_empty() {
+            queue.write_buffer(&self.instance_buffer, 0, bytemuck::cast_slice(&instance_data));
+        }
+        self.batches = batches;
+    }
+
+    // Swaps in the resources of a sheet that was reloaded from disk
+    pub fn replace_sheet(&mut self, handle: SpriteSheetHandle, resources: SheetResources) {
+        if let Some(sheet) = self.sheets.get_mut(handle.0) {
+            *sheet = Some(resources);
+            self.sheet_errors.remove(&handle);
+        }
+    }
+
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        render_pass.set_pipeline(&self.render_pipeline);
+
+        render_pass.set_bind_group(1, &self.uniform_bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
+        render_pass.set_index_buffer(self.index_buffer.slice(..));
+
+        for (handle, range) in &self.batches {
+            // Skip sheets that failed to load
+            if let Some(sheet) = self.sheets.get(handle.0).and_then(Option::as_ref) {
+                render_pass.set_bind_group(0, &sheet.bind_group, &[]);
+                render_pass.draw_indexed(0..self.num_indices, 0, range.clone());
+            }
+        }
+    }
+}
+
+pub struct State {
+    pub surface: wgpu::Surface,
+    pub device: wgpu::Device,
+    pub queue: wgpu::Queue,
+    pub sc_desc: wgpu::SwapChainDescriptor,
+    pub swap_chain: wgpu::SwapChain,
+    pub size: winit::dpi::PhysicalSize<u32>,
+    pub pointer: (f64, f64),
+    pub draw_challenge: bool,
+    pub sprites: SpriteRenderer,
+    pub imgui: ImguiState,
+    pub bg_color: [f32; 3],
+    pub depth_texture: texture::Texture,
+    // Shown in the overlay until the shaders compile again
+    pub shader_error: Option<String>,
+    // Moves the AI instances on the GPU when enabled
+    pub crowd: Option<crowd::CrowdSimulation>,
+}
+
+impl State {
+    // Creating some of the wgpu types requires async code
+    pub async fn new(window: &Window, game: &GameState) -> anyhow::Result<Self> {
+        let size = window.inner_size();
+
+        // The instance is a handle to our GPU
+        // BackendBit::PRIMARY => Vulkan + Metal + DX12 + Browser WebGPU
+        let instance = wgpu::Instance::new(wgpu::BackendBit::PRIMARY);
+        let surface = unsafe { instance.create_surface(window) };
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::HighPerformance,
+                compatible_surface: Some(&surface),
+            })
+            .await
+            .ok_or_else(|| anyhow::anyhow!("No suitable graphics adapter found"))?;
+
+        let (device, queue) = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    features: wgpu::Features::empty(),
+                    limits: wgpu::Limits::default(),
+                    shader_validation: true,
+                },
+                None, // Trace path
+            )
+            .await?;
+
+        // Describes how images are displayed to Surface
+        let sc_desc = wgpu::SwapChainDescriptor {
+            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT,
+            format: wgpu::TextureFormat::Bgra8UnormSrgb, // The screen format that is most widely available, should use the screens native format but there's no way to query it yet
+            width: size.width,
+            height: size.height,
+            present_mode: wgpu::PresentMode::Immediate,
+        };
+        let swap_chain = device.create_swap_chain(&surface, &sc_desc);
+
+        let depth_texture = texture::Texture::create_depth_texture(&device, sc_desc.width, sc_desc.height, "depth_texture");
+
+        // Set up dear imgui
+        let imgui = {
+            let mut imgui = imgui::Context::create();
+
+            let mut platform = imgui_winit_support::WinitPlatform::init(&mut imgui);
+            platform.attach_window(
+                imgui.io_mut(),
+                &window,
+                imgui_winit_support::HiDpiMode::Default,
+            );
+            imgui.set_ini_filename(None);
+
+            imgui.io_mut().mouse_pos = [0.0, 0.0];
+
+            imgui.style_mut().window_border_size = 0.0;
+            imgui.style_mut().window_padding = [10.0, 10.0];
+
+            let hidpi_factor = window.scale_factor();
+
+            let font_size = (16.0 * hidpi_factor) as f32;
+            imgui.io_mut().font_global_scale = (1.0 / hidpi_factor) as f32;
+
+            imgui.fonts().add_font(&[FontSource::DefaultFontData {
+                config: Some(imgui::FontConfig {
+                    oversample_h: 1,
+                    pixel_snap_h: true,
+                    size_pixels: font_size,
+                    ..Default::default()
+                }),
+            }]);
+
+            let renderer_config = RendererConfig {
+                texture_format: sc_desc.format,
+                ..Default::default()
+            };
+
+            let imgui_renderer = Renderer::new(&mut imgui, &device, &queue, renderer_config);
+
+            ImguiState{ctx: imgui, renderer: imgui_renderer, platform, demo_open: false}
+        };
+
+        let sprites = SpriteRenderer::new(&device, &queue, sc_desc.format, game)?;
+
+        Ok(Self {
             surface,
             device,
             queue,
@@ -441,21 +820,14 @@ impl State {
             swap_chain,
             size,
             pointer: (0.0, 0.0),
-            render_pipeline,
             draw_challenge: false,
-            vertex_buffer,
-            index_buffer,
-            num_indices,
-            diffuse_bind_group,
-            diffuse_texture,
-            uniforms,
-            uniform_buffer,
-            uniform_bind_group,
+            sprites,
             imgui,
             bg_color: [0.02, 0.02, 0.01],
-            instance_buffer,
             depth_texture,
-        }
+            shader_error: None,
+            crowd: None,
+        })
     }
 
     // To support resizing, we need to re-create the swap chain on resize event
@@ -466,7 +838,7 @@ impl State {
         self.sc_desc.height = new_size.height;
 
         self.swap_chain = self.device.create_swap_chain(&self.surface, &self.sc_desc);
-        self.depth_texture = texture::Texture::create_depth_texture(&self.device, &self.sc_desc, "depth_texture");
+        self.depth_texture = texture::Texture::create_depth_texture(&self.device, self.sc_desc.width, self.sc_desc.height, "depth_texture");
     }
 
     pub fn input(&mut self, event: &WindowEvent) -> bool {
@@ -480,12 +852,16 @@ impl State {
         }
     }
 
-    pub fn update(&mut self, game: &GameState) {
-        self.uniforms.update_view_proj(game.camera.build_view_projection_matrix().into());
-        self.queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[self.uniforms]));
+    pub fn update(&mut self, game: &GameState, alpha: f32) {
+        self.sprites.update(&self.device, &self.queue, game, alpha);
+        if let Some(crowd) = &mut self.crowd {
+            crowd.sync(&self.device, &self.queue, &self.sprites, game, alpha);
+        }
+    }
 
-        let instance_data = game.instances.iter().map(InstanceRaw::from_instance).collect::<Vec<_>>();
-        self.queue.write_buffer(&self.instance_buffer, 0, bytemuck::cast_slice(&instance_data));
+    pub fn enable_gpu_crowd(&mut self, game: &GameState) -> anyhow::Result<()> {
+        self.crowd = Some(crowd::CrowdSimulation::new(&self.device, &self.queue, &self.sprites, game)?);
+        Ok(())
     }
 
     pub fn create_render_encoder(&mut self, game: &GameState, frame: &wgpu::SwapChainTexture, winit_window: &Window) -> wgpu::CommandEncoder {
@@ -493,6 +869,10 @@ impl State {
             label: Some("Render Encoder"),
         });
 
+        if let Some(crowd) = &mut self.crowd {
+            crowd.dispatch(&self.queue, &mut encoder);
+        }
+
         {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
@@ -518,14 +898,7 @@ impl State {
                 }),
             });
 
-            render_pass.set_pipeline(&self.render_pipeline);
-
-            render_pass.set_bind_group(0, &self.diffuse_bind_group, &[]);
-            render_pass.set_bind_group(1, &self.uniform_bind_group, &[]);
-            render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
-            render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
-            render_pass.set_index_buffer(self.index_buffer.slice(..));
-            render_pass.draw_indexed(0..self.num_indices, 0, 0..game.instances.len() as _);
+            self.sprites.draw(&mut render_pass);
 
             self.imgui.platform.prepare_frame(self.imgui.ctx.io_mut(), &winit_window)
                 .expect("Failed to prepare frame");
@@ -534,7 +907,16 @@ impl State {
 
             let window = imgui::Window::new(im_str!("Hello world!"));
             let mut tmp_color = self.bg_color;
+            let shader_error = self.shader_error.clone();
+            let sheet_errors = self.sprites.sheet_errors.values().cloned().collect::<Vec<_>>();
             let time_delta_ms = match game.time_delta { Some(dur) => dur.as_millis(), None => 1 };
+            let seed = game.seed;
+            let draw_calls = self.sprites.batches.len();
+            let player = game.world.players
+                .keys()
+                .next()
+                .and_then(|id| game.world.animations.get(id))
+                .map(|animated| format!("{} {:?}", animated.state.state(), animated.direction));
 
             window
                 .always_auto_resize(true)
@@ -551,11 +933,30 @@ impl State {
                         mouse_pos[0],
                         mouse_pos[1]
                     ));
+                    ui.text(im_str!("Seed: {}", seed));
+                    ui.text(im_str!("Sprite draw calls: {}", draw_calls));
+                    if let Some(player) = &player {
+                        ui.text(im_str!("Player: {}", player));
+                    }
                     ui.separator();
                     if ColorEdit::new(im_str!("color_edit"), &mut tmp_color).build(&ui) {
                         // state.notify_text = "*** Red button was clicked";
                     }
 
+                    if let Some(error) = &shader_error {
+                        ui.separator();
+                        ui.text_colored([1.0, 0.3, 0.3, 1.0], im_str!("Shader error, keeping the previous shaders:"));
+                        ui.text(error);
+                    }
+
+                    if !sheet_errors.is_empty() {
+                        ui.separator();
+                        ui.text_colored([1.0, 0.3, 0.3, 1.0], im_str!("Sprite sheet errors:"));
+                        for error in &sheet_errors {
+                            ui.text(error);
+                        }
+                    }
+
                     style.pop(&ui);
                 });
 
@@ -586,3 +987,114 @@ impl State {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::game::{Direction, Sprite, Transform};
+    use crate::sprite_sheet::SpriteSheet;
+    use headless::HeadlessState;
+
+    #[test]
+    fn packed_sheets_are_drawn_in_one_batch() {
+        let clock = ManualClock::new();
+        let mut game = GameState::new(&clock, 0, SpriteSheet::load(SpriteSheet::default_path()).unwrap()).unwrap();
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src/happy_tree.ron");
+        let tree = game.add_sprite_sheet(SpriteSheet::load(path).unwrap());
+        let tree_id = game.world.spawn();
+        game.world.transforms.insert(tree_id, Transform::at(cgmath::Vector3::new(1.0, 0.0, 0.0)));
+        game.world.sprites.insert(tree_id, Sprite::new(tree, 0));
+
+        let (instances, batches) = SpriteRenderer::batch_instances(&game, 1.0);
+        assert_eq!(batches.len(), 2);
+
+        game.pack_sprite_sheets(1024).unwrap();
+        let (packed_instances, batches) = SpriteRenderer::batch_instances(&game, 1.0);
+        assert_eq!(batches, vec![(SpriteSheetHandle(0), 0..instances.len() as u32)]);
+
+        // The tree is the only sprite without an animation, and shows the
+        // frame that follows the 24 character frames
+        let tree_frames = packed_instances.iter().filter(|i| i.frame == 24).count();
+        assert_eq!(tree_frames, 1);
+        assert_eq!(game.sprite_sheets[0].frames.len(), 25);
+    }
+
+    #[test]
+    fn instance_capacity_doubles_until_the_instances_fit() {
+        assert_eq!(SpriteRenderer::grown_capacity(16, 0), 16);
+        assert_eq!(SpriteRenderer::grown_capacity(16, 16), 16);
+        assert_eq!(SpriteRenderer::grown_capacity(16, 17), 32);
+        assert_eq!(SpriteRenderer::grown_capacity(16, 100), 128);
+        assert_eq!(SpriteRenderer::grown_capacity(0, 3), 4);
+        assert_eq!(SpriteRenderer::grown_capacity(128, 5), 128);
+    }
+
+    #[test]
+    fn entity_ids_stay_stable_across_spawn_and_despawn() {
+        let clock = ManualClock::new();
+        let mut game = GameState::new(&clock, 0, SpriteSheet::load(SpriteSheet::default_path()).unwrap()).unwrap();
+        let position = cgmath::Vector3::new(0.0, 0.0, 0.0);
+        let before = SpriteRenderer::instance_order(&game);
+
+        let despawned = before[3];
+        assert!(game.despawn(despawned));
+        let spawned = game.spawn_character(&clock, SpriteSheetHandle(0), position, Direction::S, true);
+
+        // Every other entity keeps its id and its components, the new one gets
+        // an id that was never used
+        assert!(before.iter().all(|id| *id < spawned));
+        let after = SpriteRenderer::instance_order(&game);
+        let mut expected = before.iter().copied().filter(|id| *id != despawned).collect::<Vec<_>>();
+        expected.push(spawned);
+        assert_eq!(after, expected);
+        assert!(!game.world.ai_controllers.contains_key(&despawned));
+        assert!(game.world.ai_controllers.contains_key(&spawned));
+
+        let (instances, _) = SpriteRenderer::batch_instances(&game, 1.0);
+        assert_eq!(instances.len(), before.len());
+    }
+
+    #[test]
+    fn uploads_every_sheet_again_after_packing() {
+        let clock = ManualClock::new();
+        let mut game = GameState::new(&clock, 0, SpriteSheet::load(SpriteSheet::default_path()).unwrap()).unwrap();
+        let uploaded_generation = game.sheets_generation;
+        assert_eq!(SpriteRenderer::first_new_sheet(1, uploaded_generation, &game), 1);
+
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src/happy_tree.ron");
+        game.add_sprite_sheet(SpriteSheet::load(path).unwrap());
+        assert_eq!(SpriteRenderer::first_new_sheet(1, uploaded_generation, &game), 1);
+
+        // Two sheets were uploaded and packing left one
+        game.pack_sprite_sheets(1024).unwrap();
+        assert_eq!(SpriteRenderer::first_new_sheet(2, uploaded_generation, &game), 0);
+        assert_eq!(SpriteRenderer::first_new_sheet(1, game.sheets_generation, &game), 1);
+    }
+
+    #[test]
+    fn keeps_sheet_upload_errors_until_the_sheet_loads() {
+        let clock = ManualClock::new();
+        let mut game = GameState::new(&clock, 0, SpriteSheet::load(SpriteSheet::default_path()).unwrap()).unwrap();
+        let mut headless = match futures::executor::block_on(HeadlessState::new(64, 64, &game)) {
+            Ok(headless) => headless,
+            Err(e) => {
+                eprintln!("No graphics adapter, skipping sheet upload errors: {:?}", e);
+                return;
+            }
+        };
+
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src/happy_tree.ron");
+        let mut broken = SpriteSheet::load(&path).unwrap();
+        broken.image_path = broken.image_path.with_file_name("missing.png");
+        let handle = game.add_sprite_sheet(broken);
+        headless.update(&game, 1.0);
+        assert!(headless.sprites.sheets[handle.0].is_none());
+        assert_eq!(headless.sprites.sheet_errors.keys().copied().collect::<Vec<_>>(), vec![handle]);
+
+        let sheet = SpriteSheet::load(&path).unwrap();
+        let resources = SheetResources::new(&headless.device, &headless.queue, &headless.sprites.texture_bind_group_layout, &sheet).unwrap();
+        headless.sprites.replace_sheet(handle, resources);
+        assert!(headless.sprites.sheet_errors.is_empty());
+    }
+}
@@ -0,0 +1,550 @@
+// Optional GPU path for the crowd. Positions, velocities and the walk/stand
+// timers of the agents live in a storage buffer. A compute pass advances them
+// every tick and writes the positions straight into the instance buffer of the
+// sprite pipeline. game::ai::AIController is the reference implementation, the
+// compute shader continues the ChaCha8 stream of each agent's controller so it
+// makes the same decisions. The controllers keep running on the CPU to drive
+// the animations, only the movement is left to the GPU. Positions never come
+// back to the CPU.
+
+use anyhow::*;
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::time::Duration;
+use wgpu::util::DeviceExt;
+
+use crate::game::{ai, EntityId, GameState, Transform};
+
+use super::{shader_interface, InstanceRaw, SpriteRenderer};
+
+const WORKGROUP_SIZE: u32 = 64;
+
+// Agents the agent buffer has room for at first, doubled when full
+const INITIAL_AGENT_CAPACITY: usize = 64;
+
+// Agent::slot of agents whose entity was despawned or lost its sprite
+const NO_SLOT: u32 = u32::MAX;
+
+// Has to match Agent in crowd.comp
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct Agent {
+    position: [f32; 2],
+    previous_position: [f32; 2],
+    velocity: [f32; 2],
+    elapsed: u32,
+    duration: u32,
+    walking: u32,
+    slot: u32,
+    key: [u32; 8],
+    word_pos: u32,
+    // std430 aligns the array elements to vec2
+    padding: u32,
+}
+
+// Byte offset of Agent::slot
+const SLOT_OFFSET: usize = 3 * std::mem::size_of::<[f32; 2]>() + 3 * std::mem::size_of::<u32>();
+
+fn micros(duration: Duration) -> Result<u32> {
+    u32::try_from(duration.as_micros()).context("Duration too long for the GPU crowd")
+}
+
+impl Agent {
+    // Continues from the current state of the controller, `now` being the
+    // time of the last tick
+    fn new(controller: &ai::AIController, transform: &Transform, now: Duration) -> Result<Agent> {
+        let (started, duration, walking, velocity) = match controller.state {
+            ai::State::Standing { started, duration } => (started, duration, 0, (0.0, 0.0)),
+            ai::State::Walking { started, duration, velocity } => (started, duration, 1, velocity),
+        };
+
+        // ChaCha reads the key as little endian words
+        let (key_bytes, word_pos) = controller.random_stream();
+        let mut key = [0; 8];
+        for (word, bytes) in key.iter_mut().zip(key_bytes.chunks_exact(4)) {
+            *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+
+        Ok(Agent {
+            position: [transform.position.x, transform.position.y],
+            previous_position: [transform.previous_position.x, transform.previous_position.y],
+            velocity: [velocity.0, velocity.1],
+            elapsed: micros(now - started)?,
+            duration: micros(duration)?,
+            walking,
+            slot: NO_SLOT,
+            key,
+            word_pos: u32::try_from(word_pos).context("AI random stream too long for the GPU crowd")?,
+            padding: 0,
+        })
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct CrowdParams {
+    tick_seconds: f32,
+    tick_micros: u32,
+    ticks: u32,
+    alpha: f32,
+    agent_count: u32,
+    instance_stride: u32,
+}
+
+const _: [(); shader_interface::crowd_comp::PARAMS_SIZE] = [(); std::mem::size_of::<CrowdParams>()];
+
+pub struct CrowdSimulation {
+    pipeline: wgpu::ComputePipeline,
+    params: CrowdParams,
+    params_buffer: wgpu::Buffer,
+    agent_buffer: wgpu::Buffer,
+    // Agents the agent buffer has room for
+    agent_capacity: usize,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    // Entity and instance buffer slot of every agent. Agents of despawned
+    // entities are None and their index is reused by the next spawn.
+    entities: Vec<Option<EntityId>>,
+    slots: Vec<u32>,
+    // Capacity of the instance buffer the bind group refers to
+    instance_capacity: usize,
+}
+
+impl CrowdSimulation {
+    // Instance buffer slot of every agent, NO_SLOT for entities that aren't drawn
+    fn slots(entities: &[Option<EntityId>], game: &GameState) -> Vec<u32> {
+        let slot_of = SpriteRenderer::instance_order(game)
+            .into_iter()
+            .enumerate()
+            .map(|(slot, id)| (id, slot as u32))
+            .collect::<HashMap<_, _>>();
+        entities
+            .iter()
+            .map(|id| id.and_then(|id| slot_of.get(&id).copied()).unwrap_or(NO_SLOT))
+            .collect()
+    }
+
+    // Entities the crowd moves, every one with an AI controller and a transform
+    fn crowd_entities(game: &GameState) -> BTreeSet<EntityId> {
+        game.world.ai_controllers
+            .keys()
+            .filter(|id| game.world.transforms.contains_key(id))
+            .copied()
+            .collect()
+    }
+
+    // Frees the agents of entities that left the crowd and assigns an agent
+    // index to every entity that joined it. Returns the joined entities with
+    // their index.
+    fn update_entities(entities: &mut Vec<Option<EntityId>>, crowd: &BTreeSet<EntityId>) -> Vec<(usize, EntityId)> {
+        for entity in entities.iter_mut() {
+            if entity.map_or(false, |id| !crowd.contains(&id)) {
+                *entity = None;
+            }
+        }
+
+        let existing = entities.iter().flatten().copied().collect::<BTreeSet<_>>();
+        let mut joined = Vec::new();
+        let mut free = 0;
+        for id in crowd.difference(&existing) {
+            while free < entities.len() && entities[free].is_some() {
+                free += 1;
+            }
+            if free == entities.len() {
+                entities.push(None);
+            }
+            entities[free] = Some(*id);
+            joined.push((free, *id));
+        }
+        joined
+    }
+
+    fn create_agent_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Crowd Agent Buffer"),
+            size: (capacity * std::mem::size_of::<Agent>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::COPY_SRC,
+            mapped_at_creation: false,
+        })
+    }
+
+    // Takes over moving every entity with an AI controller and a transform,
+    // and the ones spawned later
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, sprites: &SpriteRenderer, game: &GameState) -> Result<Self> {
+        let mut entities = Vec::new();
+        let joined = Self::update_entities(&mut entities, &Self::crowd_entities(game));
+
+        let agent_capacity = entities.len().max(INITIAL_AGENT_CAPACITY);
+        let agent_buffer = Self::create_agent_buffer(device, agent_capacity);
+
+        let params = CrowdParams {
+            tick_seconds: 0.0,
+            tick_micros: 0,
+            ticks: 0,
+            alpha: 1.0,
+            agent_count: 0,
+            instance_stride: (std::mem::size_of::<InstanceRaw>() / 4) as u32,
+        };
+        let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Crowd Params Buffer"),
+            contents: bytemuck::cast_slice(&[params]),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+
+        let storage = wgpu::BindingType::StorageBuffer {
+            dynamic: false,
+            min_binding_size: None,
+            readonly: false,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::COMPUTE,
+                    ty: wgpu::BindingType::UniformBuffer {
+                        dynamic: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::COMPUTE,
+                    ty: storage.clone(),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStage::COMPUTE,
+                    ty: storage,
+                    count: None,
+                },
+            ],
+            label: Some("crowd_bind_group_layout"),
+        });
+
+        let bind_group = Self::create_bind_group(device, &bind_group_layout, &params_buffer, &agent_buffer, &sprites.instance_buffer);
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Crowd Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let cs_module = device.create_shader_module(wgpu::include_spirv!("../crowd.comp.spv"));
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Crowd Pipeline"),
+            layout: Some(&pipeline_layout),
+            compute_stage: wgpu::ProgrammableStageDescriptor {
+                module: &cs_module,
+                entry_point: "main",
+            },
+        });
+
+        let mut crowd = Self {
+            pipeline,
+            params,
+            params_buffer,
+            agent_buffer,
+            agent_capacity,
+            bind_group_layout,
+            bind_group,
+            slots: vec![NO_SLOT; entities.len()],
+            entities,
+            instance_capacity: sprites.instance_capacity,
+        };
+        crowd.write_agents(queue, game, &joined)?;
+        crowd.sync(device, queue, sprites, game, 1.0);
+        Ok(crowd)
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        params_buffer: &wgpu::Buffer,
+        agent_buffer: &wgpu::Buffer,
+        instance_buffer: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer(params_buffer.slice(..)),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Buffer(agent_buffer.slice(..)),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Buffer(instance_buffer.slice(..)),
+                },
+            ],
+            label: Some("crowd_bind_group"),
+        })
+    }
+
+    // Uploads the agents of joined entities, starting from the state their
+    // controllers are in after the last tick
+    fn write_agents(&mut self, queue: &wgpu::Queue, game: &GameState, joined: &[(usize, EntityId)]) -> Result<()> {
+        for (index, id) in joined {
+            let agent = Agent::new(&game.world.ai_controllers[id], &game.world.transforms[id], game.last_update)?;
+            let offset = index * std::mem::size_of::<Agent>();
+            queue.write_buffer(&self.agent_buffer, offset as wgpu::BufferAddress, bytemuck::bytes_of(&agent));
+            self.slots[*index] = NO_SLOT;
+        }
+        self.params.agent_count = self.entities.len() as u32;
+        Ok(())
+    }
+
+    // Moves the agents into a buffer with room for `capacity` agents
+    fn grow(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, sprites: &SpriteRenderer, capacity: usize) {
+        let agent_buffer = Self::create_agent_buffer(device, capacity);
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Crowd Grow Encoder"),
+        });
+        let size = (self.agent_capacity * std::mem::size_of::<Agent>()) as wgpu::BufferAddress;
+        encoder.copy_buffer_to_buffer(&self.agent_buffer, 0, &agent_buffer, 0, size);
+        queue.submit(std::iter::once(encoder.finish()));
+
+        self.agent_buffer = agent_buffer;
+        self.agent_capacity = capacity;
+        self.bind_group = Self::create_bind_group(
+            device,
+            &self.bind_group_layout,
+            &self.params_buffer,
+            &self.agent_buffer,
+            &sprites.instance_buffer,
+        );
+    }
+
+    // Follows spawns and despawns moving entities around in the instance
+    // buffer, and the instance buffer being reallocated when it grows
+    pub fn sync(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, sprites: &SpriteRenderer, game: &GameState, alpha: f32) {
+        self.params.alpha = alpha;
+
+        if sprites.instance_capacity != self.instance_capacity {
+            self.bind_group = Self::create_bind_group(
+                device,
+                &self.bind_group_layout,
+                &self.params_buffer,
+                &self.agent_buffer,
+                &sprites.instance_buffer,
+            );
+            self.instance_capacity = sprites.instance_capacity;
+        }
+
+        let slots = Self::slots(&self.entities, game);
+        for (i, (old, new)) in self.slots.iter().zip(&slots).enumerate() {
+            if old != new {
+                let offset = i * std::mem::size_of::<Agent>() + SLOT_OFFSET;
+                queue.write_buffer(&self.agent_buffer, offset as wgpu::BufferAddress, bytemuck::bytes_of(new));
+            }
+        }
+        self.slots = slots;
+    }
+
+    // Advances the agents by one tick, after GameState::update ran the same
+    // tick on the CPU. AI entities spawned during it join the crowd in the
+    // state the tick left them in, despawned ones leave it.
+    pub fn tick(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        sprites: &SpriteRenderer,
+        game: &GameState,
+        tick_duration: Duration,
+    ) -> Result<()> {
+        self.params.ticks = 1;
+        self.params.tick_seconds = tick_duration.as_secs_f32();
+        self.params.tick_micros = micros(tick_duration)?;
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Crowd Tick Encoder"),
+        });
+        self.dispatch(queue, &mut encoder);
+        queue.submit(std::iter::once(encoder.finish()));
+
+        // Joined after the dispatch, their controllers already ran this tick
+        let joined = Self::update_entities(&mut self.entities, &Self::crowd_entities(game));
+        self.slots.resize(self.entities.len(), NO_SLOT);
+        if self.entities.len() > self.agent_capacity {
+            self.grow(device, queue, sprites, self.entities.len().max(self.agent_capacity * 2));
+        }
+        self.write_agents(queue, game, &joined)
+    }
+
+    // Records a pass with the current params, then resets them to a frame
+    // pass. A frame pass doesn't move the agents, it writes their
+    // interpolated positions into the instance buffer and has to be recorded
+    // after the instance data of the frame is uploaded, which overwrites the
+    // positions written by the previous one.
+    pub fn dispatch(&mut self, queue: &wgpu::Queue, encoder: &mut wgpu::CommandEncoder) {
+        queue.write_buffer(&self.params_buffer, 0, bytemuck::cast_slice(&[self.params]));
+        self.params.ticks = 0;
+        if self.params.agent_count == 0 {
+            return;
+        }
+
+        let mut compute_pass = encoder.begin_compute_pass();
+        compute_pass.set_pipeline(&self.pipeline);
+        compute_pass.set_bind_group(0, &self.bind_group, &[]);
+        let workgroups = (self.params.agent_count + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE;
+        compute_pass.dispatch(workgroups, 1, 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+    use crate::clock::{Clock, ManualClock};
+    use crate::game::Direction;
+    use crate::rendering::headless::HeadlessState;
+    use crate::sprite_sheet::{SpriteSheet, SpriteSheetHandle};
+
+    // chacha8_word of crowd.comp
+    fn chacha8_word(key: &[u32; 8], word_pos: u32) -> u32 {
+        fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+            x[a] = x[a].wrapping_add(x[b]); x[d] = (x[d] ^ x[a]).rotate_left(16);
+            x[c] = x[c].wrapping_add(x[d]); x[b] = (x[b] ^ x[c]).rotate_left(12);
+            x[a] = x[a].wrapping_add(x[b]); x[d] = (x[d] ^ x[a]).rotate_left(8);
+            x[c] = x[c].wrapping_add(x[d]); x[b] = (x[b] ^ x[c]).rotate_left(7);
+        }
+
+        let mut initial = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574, 0, 0, 0, 0, 0, 0, 0, 0, word_pos / 16, 0, 0, 0];
+        initial[4..12].copy_from_slice(key);
+        let mut x = initial;
+        for _ in 0..4 {
+            quarter_round(&mut x, 0, 4, 8, 12);
+            quarter_round(&mut x, 1, 5, 9, 13);
+            quarter_round(&mut x, 2, 6, 10, 14);
+            quarter_round(&mut x, 3, 7, 11, 15);
+            quarter_round(&mut x, 0, 5, 10, 15);
+            quarter_round(&mut x, 1, 6, 11, 12);
+            quarter_round(&mut x, 2, 7, 8, 13);
+            quarter_round(&mut x, 3, 4, 9, 14);
+        }
+        let i = (word_pos % 16) as usize;
+        x[i].wrapping_add(initial[i])
+    }
+
+    // The shader only runs on a GPU, this checks the stream layout it relies
+    // on against rand_chacha, past the 4 block buffer of ChaCha8Rng
+    #[test]
+    fn shader_rng_matches_chacha8() {
+        let clock = ManualClock::new();
+        let controller = ai::AIController::new(&clock, 5);
+        let transform = Transform::at(cgmath::Vector3::new(0.0, 0.0, 0.0));
+        let agent = Agent::new(&controller, &transform, clock.now()).unwrap();
+        assert_eq!(agent.word_pos, 0);
+
+        let (key, _) = controller.random_stream();
+        let mut rng = ChaCha8Rng::from_seed(key);
+        for word_pos in 0..200 {
+            assert_eq!(chacha8_word(&agent.key, word_pos), rng.gen::<u32>());
+        }
+
+        // gen::<u64>() takes the low word first
+        let mut rng = ChaCha8Rng::from_seed(key);
+        let expected = rng.gen::<u64>() % 1000;
+        let (low, high) = (chacha8_word(&agent.key, 0), chacha8_word(&agent.key, 1));
+        assert_eq!(((high % 1000) * 296 + low % 1000) % 1000, expected as u32);
+    }
+
+    #[test]
+    fn reuses_agents_of_despawned_entities() {
+        let clock = ManualClock::new();
+        let mut game = GameState::new(&clock, 7, SpriteSheet::load(SpriteSheet::default_path()).unwrap()).unwrap();
+        let mut entities = Vec::new();
+        let joined = CrowdSimulation::update_entities(&mut entities, &CrowdSimulation::crowd_entities(&game));
+        assert_eq!(joined.len(), game.world.ai_controllers.len());
+
+        let despawned = entities[3].unwrap();
+        game.despawn(despawned);
+        let spawned = game.spawn_character(&clock, SpriteSheetHandle(0), cgmath::Vector3::new(0.0, 0.0, 0.0), Direction::S, true);
+        let joined = CrowdSimulation::update_entities(&mut entities, &CrowdSimulation::crowd_entities(&game));
+        assert_eq!(joined, vec![(3, spawned)]);
+        assert_eq!(entities.len(), game.world.ai_controllers.len());
+
+        let spawned = game.spawn_character(&clock, SpriteSheetHandle(0), cgmath::Vector3::new(0.0, 0.0, 0.0), Direction::S, true);
+        let joined = CrowdSimulation::update_entities(&mut entities, &CrowdSimulation::crowd_entities(&game));
+        assert_eq!(joined, vec![(entities.len() - 1, spawned)]);
+    }
+
+    async fn read_agents(crowd: &CrowdSimulation, device: &wgpu::Device, queue: &wgpu::Queue) -> Vec<Agent> {
+        let size = (crowd.entities.len() * std::mem::size_of::<Agent>()) as wgpu::BufferAddress;
+        let readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Crowd Readback Buffer"),
+            size,
+            usage: wgpu::BufferUsage::MAP_READ | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        encoder.copy_buffer_to_buffer(&crowd.agent_buffer, 0, &readback_buffer, 0, size);
+        queue.submit(std::iter::once(encoder.finish()));
+
+        let buffer_slice = readback_buffer.slice(..);
+        let mapping = buffer_slice.map_async(wgpu::MapMode::Read);
+        device.poll(wgpu::Maintain::Wait);
+        mapping.await.unwrap();
+        let data = buffer_slice.get_mapped_range();
+        bytemuck::cast_slice::<u8, Agent>(&data).to_vec()
+    }
+
+    // Needs a graphics adapter, skipped on machines without one
+    #[test]
+    fn matches_the_cpu_reference() {
+        let mut clock = ManualClock::new();
+        let sprite_sheet = || SpriteSheet::load(SpriteSheet::default_path()).unwrap();
+        let mut cpu_game = GameState::new(&clock, 7, sprite_sheet()).unwrap();
+        let mut gpu_game = GameState::new(&clock, 7, sprite_sheet()).unwrap();
+        gpu_game.move_ai = false;
+
+        let headless = match futures::executor::block_on(HeadlessState::new(64, 64, &gpu_game)) {
+            Ok(headless) => headless,
+            Err(e) => {
+                eprintln!("No graphics adapter, skipping GPU crowd: {:?}", e);
+                return;
+            }
+        };
+        let (device, queue) = (&headless.device, &headless.queue);
+        let mut crowd = CrowdSimulation::new(device, queue, &headless.sprites, &gpu_game).unwrap();
+
+        // Long enough for every agent to walk and stand a few times, with a
+        // despawn and a spawn half way
+        let tick_duration = Duration::from_millis(10);
+        for tick in 0..1000 {
+            clock.advance(tick_duration);
+            if tick == 500 {
+                for game in [&mut cpu_game, &mut gpu_game].iter_mut() {
+                    let first = game.world.ai_controllers.keys().next().copied().unwrap();
+                    game.despawn(first);
+                    game.spawn_character(&clock, SpriteSheetHandle(0), cgmath::Vector3::new(1.0, 1.0, 0.0), Direction::S, true);
+                }
+            }
+            cpu_game.update(&clock);
+            gpu_game.update(&clock);
+            crowd.tick(device, queue, &headless.sprites, &gpu_game, tick_duration).unwrap();
+        }
+
+        let agents = futures::executor::block_on(read_agents(&crowd, device, queue));
+        let mut compared = 0;
+        for (id, agent) in crowd.entities.iter().zip(&agents) {
+            let id = match id {
+                Some(id) => id,
+                None => continue,
+            };
+            let expected = cpu_game.world.transforms[id].position;
+            assert!((agent.position[0] - expected.x).abs() < 1e-3, "{:?} != {:?}", agent.position, expected);
+            assert!((agent.position[1] - expected.y).abs() < 1e-3, "{:?} != {:?}", agent.position, expected);
+
+            let walking = matches!(cpu_game.world.ai_controllers[id].state, ai::State::Walking { .. });
+            assert_eq!(agent.walking == 1, walking);
+            assert_eq!(u128::from(agent.word_pos), cpu_game.world.ai_controllers[id].random_stream().1);
+            compared += 1;
+        }
+        assert_eq!(compared, cpu_game.world.ai_controllers.len());
+    }
+}
@@ -0,0 +1,183 @@
+use anyhow::*;
+use std::path::Path;
+
+use crate::texture;
+use crate::game::GameState;
+
+use super::SpriteRenderer;
+
+/// Renders the sprite pipeline into an offscreen texture instead of a swap
+/// chain, so frames can be produced and read back without a window.
+pub struct HeadlessState {
+    pub device: wgpu::Device,
+    pub queue: wgpu::Queue,
+    pub width: u32,
+    pub height: u32,
+    pub target: wgpu::Texture,
+    pub target_view: wgpu::TextureView,
+    pub depth_texture: texture::Texture,
+    pub sprites: SpriteRenderer,
+    pub bg_color: [f32; 3],
+    readback_buffer: wgpu::Buffer,
+    padded_bytes_per_row: u32,
+}
+
+impl HeadlessState {
+    pub const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+    pub async fn new(width: u32, height: u32, game: &GameState) -> Result<Self> {
+        // No surface to be compatible with, so any adapter will do. On machines
+        // without a GPU this picks up software Vulkan drivers such as lavapipe.
+        let instance = wgpu::Instance::new(wgpu::BackendBit::PRIMARY);
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::LowPower,
+                compatible_surface: None,
+            })
+            .await
+            .context("No suitable graphics adapter found")?;
+
+        let (device, queue) = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    features: wgpu::Features::empty(),
+                    limits: wgpu::Limits::default(),
+                    shader_validation: true,
+                },
+                None, // Trace path
+            )
+            .await?;
+
+        let target = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("headless_target"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Self::FORMAT,
+            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::COPY_SRC,
+        });
+        let target_view = target.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let depth_texture = texture::Texture::create_depth_texture(&device, width, height, "depth_texture");
+
+        // Rows copied out of a texture have to be aligned, the padding is
+        // stripped again when the image is read back
+        let unpadded_bytes_per_row = 4 * width;
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_bytes_per_row = (unpadded_bytes_per_row + align - 1) / align * align;
+
+        let readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Readback Buffer"),
+            size: (padded_bytes_per_row * height) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::MAP_READ | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let sprites = SpriteRenderer::new(&device, &queue, Self::FORMAT, game)?;
+
+        Ok(Self {
+            device,
+            queue,
+            width,
+            height,
+            target,
+            target_view,
+            depth_texture,
+            sprites,
+            bg_color: [0.02, 0.02, 0.01],
+            readback_buffer,
+            padded_bytes_per_row,
+        })
+    }
+
+    pub fn update(&mut self, game: &GameState, alpha: f32) {
+        self.sprites.update(&self.device, &self.queue, game, alpha);
+    }
+
+    pub async fn render(&mut self) -> Result<image::RgbaImage> {
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Headless Render Encoder"),
+        });
+
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: &self.target_view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color {
+                            r: f64::from(self.bg_color[0]),
+                            g: f64::from(self.bg_color[1]),
+                            b: f64::from(self.bg_color[2]),
+                            a: 1.0,
+                        }),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
+                    attachment: &self.depth_texture.view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+
+            self.sprites.draw(&mut render_pass);
+        }
+
+        encoder.copy_texture_to_buffer(
+            wgpu::TextureCopyView {
+                texture: &self.target,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            wgpu::BufferCopyView {
+                buffer: &self.readback_buffer,
+                layout: wgpu::TextureDataLayout {
+                    offset: 0,
+                    bytes_per_row: self.padded_bytes_per_row,
+                    rows_per_image: self.height,
+                },
+            },
+            wgpu::Extent3d {
+                width: self.width,
+                height: self.height,
+                depth: 1,
+            },
+        );
+
+        self.queue.submit(std::iter::once(encoder.finish()));
+
+        let buffer_slice = self.readback_buffer.slice(..);
+        let mapping = buffer_slice.map_async(wgpu::MapMode::Read);
+        self.device.poll(wgpu::Maintain::Wait);
+        mapping.await.map_err(|_| anyhow!("Failed to map readback buffer"))?;
+
+        let mut pixels = Vec::with_capacity((4 * self.width * self.height) as usize);
+        {
+            let data = buffer_slice.get_mapped_range();
+            for row in data.chunks(self.padded_bytes_per_row as usize) {
+                pixels.extend_from_slice(&row[..(4 * self.width) as usize]);
+            }
+        }
+        self.readback_buffer.unmap();
+
+        image::RgbaImage::from_raw(self.width, self.height, pixels)
+            .context("Readback buffer does not match the target size")
+    }
+
+    pub async fn render_to_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let frame = self.render().await?;
+        frame.save(path)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(())
+    }
+}
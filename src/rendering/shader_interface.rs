@@ -0,0 +1,227 @@
+// Vertex inputs and uniform block sizes of the compiled shaders, reflected by
+// build.rs so the Rust side layouts can be checked against them
+
+// Generated for every shader, not all of them are checked
+#[allow(dead_code)]
+mod generated {
+    include!(concat!(env!("OUT_DIR"), "/shader_interface.rs"));
+}
+pub use generated::*;
+
+// Whether every input the shader reads comes from one of the vertex buffer
+// attributes, in the same format. A const fn so the layouts are checked when
+// compiling, see the assertion next to the vertex types.
+pub const fn vertex_inputs_match(
+    inputs: &[(u32, wgpu::VertexFormat)],
+    attributes: &[&[wgpu::VertexAttributeDescriptor]],
+) -> bool {
+    let mut i = 0;
+    while i < inputs.len() {
+        let (location, format) = inputs[i];
+        let mut found = false;
+        let mut buffer = 0;
+        while buffer < attributes.len() {
+            let mut a = 0;
+            while a < attributes[buffer].len() {
+                let attribute = &attributes[buffer][a];
+                if attribute.shader_location == location && attribute.format as u32 == format as u32 {
+                    found = true;
+                }
+                a += 1;
+            }
+            buffer += 1;
+        }
+        if !found {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+// Shaders compiled at runtime are checked the same way, by reflecting the
+// SPIR-V before building a pipeline from it. wgpu panics on shaders that
+// don't match the pipeline layout instead of returning an error.
+#[cfg(feature = "shader-hot-reload")]
+mod runtime {
+    use anyhow::*;
+    use spirv_reflect::types::ReflectDescriptorType;
+    use wgpu::VertexFormat;
+
+    include!("shader_interface/reflect.rs");
+
+    /// A binding the pipeline layout provides, with the size of the Rust side
+    /// struct for uniform blocks
+    pub struct Binding {
+        pub set: u32,
+        pub binding: u32,
+        pub ty: ReflectDescriptorType,
+        pub visibility: wgpu::ShaderStage,
+        pub size: Option<usize>,
+    }
+
+    pub struct Reflected {
+        module: spirv_reflect::ShaderModule,
+    }
+
+    impl Reflected {
+        pub fn new(spirv: &[u32]) -> Result<Self> {
+            let module = spirv_reflect::ShaderModule::load_u32_data(spirv).map_err(|e| anyhow!(e))?;
+            Ok(Self { module })
+        }
+
+        // Every input the shader reads has to come from one of the vertex
+        // buffers, in the same format
+        pub fn check_vertex_inputs(&self, shader: &str, buffers: &[wgpu::VertexBufferDescriptor]) -> Result<()> {
+            let inputs = vertex_inputs(&self.module).with_context(|| format!("Failed to reflect {}", shader))?;
+            for (location, format) in inputs {
+                let attribute = buffers
+                    .iter()
+                    .flat_map(|buffer| buffer.attributes.iter())
+                    .find(|attribute| attribute.shader_location == location)
+                    .with_context(|| format!("{} reads location {} but no vertex buffer provides it", shader, location))?;
+                ensure!(
+                    attribute.format == format,
+                    "{} reads location {} as {:?} but the vertex buffer provides {:?}",
+                    shader,
+                    location,
+                    format,
+                    attribute.format
+                );
+            }
+            Ok(())
+        }
+
+        // Every resource the shader uses has to be in the layout, visible to
+        // the stage, of the same type and uniform blocks of the same size
+        pub fn check_bindings(&self, shader: &str, stage: wgpu::ShaderStage, layout: &[Binding]) -> Result<()> {
+            for used in self.module.enumerate_descriptor_bindings(None).map_err(|e| anyhow!(e))? {
+                let binding = layout
+                    .iter()
+                    .find(|b| b.set == used.set && b.binding == used.binding)
+                    .with_context(|| format!("{} uses set {}, binding {} which the pipeline layout doesn't have", shader, used.set, used.binding))?;
+                ensure!(
+                    binding.ty == used.descriptor_type,
+                    "{} uses set {}, binding {} as {:?} but the pipeline layout has {:?}",
+                    shader,
+                    used.set,
+                    used.binding,
+                    used.descriptor_type,
+                    binding.ty
+                );
+                ensure!(
+                    binding.visibility.contains(stage),
+                    "{} uses set {}, binding {} which is not visible to {:?}",
+                    shader,
+                    used.set,
+                    used.binding,
+                    stage
+                );
+                if let Some(size) = binding.size {
+                    ensure!(
+                        used.block.size as usize == size,
+                        "{} declares set {}, binding {} as {} bytes but the Rust side is {} bytes",
+                        shader,
+                        used.set,
+                        used.binding,
+                        used.block.size,
+                        size
+                    );
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const LAYOUT: &[Binding] = &[
+            Binding { set: 0, binding: 0, ty: ReflectDescriptorType::UniformBuffer, visibility: wgpu::ShaderStage::VERTEX, size: Some(64) },
+        ];
+
+        fn compile(src: &str) -> Reflected {
+            let mut compiler = shaderc::Compiler::new().unwrap();
+            let compiled = compiler
+                .compile_into_spirv(src, shaderc::ShaderKind::Vertex, "test.vert", "main", None)
+                .unwrap();
+            Reflected::new(compiled.as_binary()).unwrap()
+        }
+
+        fn vertex_shader(block: &str) -> String {
+            format!(
+                "#version 450\nlayout(location=0) in vec3 a_position;\nlayout(location=1) in mat4 model;\n{}\nvoid main() {{ gl_Position = u_view_proj * model * vec4(a_position, 1.0); }}\n",
+                block
+            )
+        }
+
+        #[test]
+        fn reflects_vertex_inputs() {
+            let shader = compile(&vertex_shader("layout(set=0, binding=0) uniform Uniforms { mat4 u_view_proj; };"));
+            let mut expected = vec![(0, wgpu::VertexFormat::Float3)];
+            expected.extend((1..5).map(|location| (location, wgpu::VertexFormat::Float4)));
+            assert_eq!(vertex_inputs(&shader.module).unwrap(), expected);
+            assert!(shader.check_bindings("test.vert", wgpu::ShaderStage::VERTEX, LAYOUT).is_ok());
+        }
+
+        #[test]
+        fn rejects_vertex_inputs_the_buffers_lack() {
+            let shader = compile(&vertex_shader("layout(set=0, binding=0) uniform Uniforms { mat4 u_view_proj; };"));
+            let mut attributes = vec![wgpu::VertexAttributeDescriptor { offset: 0, shader_location: 0, format: wgpu::VertexFormat::Float3 }];
+            attributes.extend((1..5).map(|location| wgpu::VertexAttributeDescriptor {
+                offset: 16 * (location as u64 - 1),
+                shader_location: location,
+                format: wgpu::VertexFormat::Float4,
+            }));
+            fn buffer(attributes: &[wgpu::VertexAttributeDescriptor]) -> wgpu::VertexBufferDescriptor<'_> {
+                wgpu::VertexBufferDescriptor { stride: 64, step_mode: wgpu::InputStepMode::Vertex, attributes }
+            }
+
+            assert!(shader.check_vertex_inputs("test.vert", &[buffer(&attributes)]).is_ok());
+            assert!(shader.check_vertex_inputs("test.vert", &[buffer(&attributes[..4])]).is_err());
+            attributes[0].format = wgpu::VertexFormat::Float4;
+            assert!(shader.check_vertex_inputs("test.vert", &[buffer(&attributes)]).is_err());
+        }
+
+        #[test]
+        fn rejects_bindings_the_layout_lacks() {
+            let shader = compile(&vertex_shader("layout(set=1, binding=0) uniform Uniforms { mat4 u_view_proj; };"));
+            assert!(shader.check_bindings("test.vert", wgpu::ShaderStage::VERTEX, LAYOUT).is_err());
+        }
+
+        #[test]
+        fn rejects_uniform_blocks_of_another_size() {
+            let shader = compile(&vertex_shader("layout(set=0, binding=0) uniform Uniforms { mat4 u_view_proj; vec4 u_extra; };"));
+            assert!(shader.check_bindings("test.vert", wgpu::ShaderStage::VERTEX, LAYOUT).is_err());
+        }
+
+        #[test]
+        fn rejects_bindings_not_visible_to_the_stage() {
+            let shader = compile(&vertex_shader("layout(set=0, binding=0) uniform Uniforms { mat4 u_view_proj; };"));
+            assert!(shader.check_bindings("test.vert", wgpu::ShaderStage::FRAGMENT, LAYOUT).is_err());
+        }
+    }
+}
+
+#[cfg(feature = "shader-hot-reload")]
+pub use runtime::{Binding, Reflected};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ATTRIBUTES: &[wgpu::VertexAttributeDescriptor] = &[
+        wgpu::VertexAttributeDescriptor { offset: 0, shader_location: 0, format: wgpu::VertexFormat::Float3 },
+        wgpu::VertexAttributeDescriptor { offset: 12, shader_location: 2, format: wgpu::VertexFormat::Uint },
+    ];
+
+    #[test]
+    fn vertex_inputs_need_an_attribute_of_the_same_format() {
+        let inputs = [(0, wgpu::VertexFormat::Float3), (2, wgpu::VertexFormat::Uint)];
+        assert!(vertex_inputs_match(&inputs, &[ATTRIBUTES]));
+        assert!(vertex_inputs_match(&inputs[..1], &[&[], ATTRIBUTES]));
+        assert!(!vertex_inputs_match(&[(1, wgpu::VertexFormat::Float3)], &[ATTRIBUTES]));
+        assert!(!vertex_inputs_match(&[(2, wgpu::VertexFormat::Int)], &[ATTRIBUTES]));
+    }
+}
@@ -0,0 +1,47 @@
+// Vertex input reflection shared by build.rs and the runtime shader checks
+// through include!. Both bring a VertexFormat into scope, build.rs can't
+// depend on wgpu and has a copy of the variants it needs.
+
+fn vertex_format(format: spirv_reflect::types::ReflectFormat) -> Result<VertexFormat> {
+    use spirv_reflect::types::ReflectFormat::*;
+    Ok(match format {
+        R32_UINT => VertexFormat::Uint,
+        R32G32_UINT => VertexFormat::Uint2,
+        R32G32B32_UINT => VertexFormat::Uint3,
+        R32G32B32A32_UINT => VertexFormat::Uint4,
+        R32_SINT => VertexFormat::Int,
+        R32G32_SINT => VertexFormat::Int2,
+        R32G32B32_SINT => VertexFormat::Int3,
+        R32G32B32A32_SINT => VertexFormat::Int4,
+        R32_SFLOAT => VertexFormat::Float,
+        R32G32_SFLOAT => VertexFormat::Float2,
+        R32G32B32_SFLOAT => VertexFormat::Float3,
+        R32G32B32A32_SFLOAT => VertexFormat::Float4,
+        _ => bail!("Unsupported vertex input format {:?}", format),
+    })
+}
+
+// Location and format of every vertex input, sorted by location
+fn vertex_inputs(module: &spirv_reflect::ShaderModule) -> Result<Vec<(u32, VertexFormat)>> {
+    let mut inputs = Vec::new();
+    for input in module.enumerate_input_variables(None).map_err(|e| anyhow!(e))? {
+        if input.decoration_flags.contains(spirv_reflect::types::ReflectDecorationFlags::BUILT_IN) {
+            continue;
+        }
+        // A matrix takes one location per column
+        let columns = input.numeric.matrix.column_count;
+        if columns > 0 {
+            let format = match input.numeric.matrix.row_count {
+                2 => VertexFormat::Float2,
+                3 => VertexFormat::Float3,
+                _ => VertexFormat::Float4,
+            };
+            inputs.extend((0..columns).map(|column| (input.location + column, format)));
+        } else {
+            let format = vertex_format(input.format).with_context(|| format!("Input {}", input.name))?;
+            inputs.push((input.location, format));
+        }
+    }
+    inputs.sort_by_key(|(location, _)| *location);
+    Ok(inputs)
+}
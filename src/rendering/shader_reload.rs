@@ -0,0 +1,147 @@
+// Recompiles the sprite shaders in-process when their sources change, so
+// shader edits show up without restarting. Only built with the
+// shader-hot-reload feature, release builds use the SPIR-V from build.rs.
+
+use anyhow::*;
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime};
+
+use crate::assets;
+use crate::clock::Clock;
+
+include!("shader_reload/includes.rs");
+include!("shader_reload/permutations.rs");
+
+// The fragment shader of the sprite pipeline is this permutation of
+// shader.frag, see SpriteRenderer::new
+const FRAGMENT_PERMUTATION: &str = "alpha_test";
+
+pub struct ShaderReloader {
+    compiler: shaderc::Compiler,
+    vertex_path: PathBuf,
+    fragment_path: PathBuf,
+    include_dir: PathBuf,
+    // Read once at startup, changing the manifest needs a restart
+    fragment_defines: Vec<(String, Option<String>)>,
+    interval: Duration,
+    last_poll: Duration,
+    // Sources and everything they included in the last compile
+    watched: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+impl ShaderReloader {
+    pub fn new(interval: Duration, clock: &dyn Clock) -> Result<Self> {
+        let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        let vertex_path = src.join("shader.vert");
+        let fragment_path = src.join("shader.frag");
+        let include_dir = src.join("shaders/include");
+        let manifest = src.join("shaders/permutations.ron");
+        let fragment_defines = read_permutations(&manifest)?
+            .into_iter()
+            .find(|p| p.shader == Path::new("shader.frag") && p.name == FRAGMENT_PERMUTATION)
+            .with_context(|| format!("{} has no {} permutation of shader.frag", manifest.display(), FRAGMENT_PERMUTATION))?
+            .macro_definitions();
+        let watched = Self::watch(vec![vertex_path.clone(), fragment_path.clone()]);
+
+        Ok(Self {
+            compiler: shaderc::Compiler::new().context("Unable to create shader compiler")?,
+            vertex_path,
+            fragment_path,
+            include_dir,
+            fragment_defines,
+            interval,
+            last_poll: clock.now(),
+            watched,
+        })
+    }
+
+    fn watch(paths: Vec<PathBuf>) -> Vec<(PathBuf, Option<SystemTime>)> {
+        paths.into_iter().map(|path| {
+            let modified = assets::modified(&path);
+            (path, modified)
+        }).collect()
+    }
+
+    // Appends the files included by the shader to `included`
+    fn compile(
+        &mut self,
+        path: &Path,
+        kind: shaderc::ShaderKind,
+        defines: &[(String, Option<String>)],
+        included: &mut Vec<PathBuf>,
+    ) -> Result<Vec<u32>> {
+        let src = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+
+        let included_files = Rc::new(RefCell::new(Vec::new()));
+        let mut options = shaderc::CompileOptions::new().context("Unable to create shader compile options")?;
+        for (name, value) in defines {
+            options.add_macro_definition(name, value.as_deref());
+        }
+        set_include_callback(&mut options, self.include_dir.clone(), included_files.clone());
+
+        let compiled = self.compiler.compile_into_spirv(&src, kind, &path.to_string_lossy(), "main", Some(&options));
+        included.extend(included_files.borrow().iter().cloned());
+        Ok(compiled?.as_binary().to_vec())
+    }
+
+    // SPIR-V of both shaders if either changed since the last poll, or the
+    // compiler error. None when nothing changed.
+    pub fn poll(&mut self, clock: &dyn Clock) -> Option<Result<(Vec<u32>, Vec<u32>)>> {
+        let now = clock.now();
+        if now - self.last_poll < self.interval {
+            return None;
+        }
+        self.last_poll = now;
+
+        if self.watched.iter().all(|(path, modified)| assets::modified(path) == *modified) {
+            return None;
+        }
+
+        let (vertex_path, fragment_path) = (self.vertex_path.clone(), self.fragment_path.clone());
+        let mut files = vec![vertex_path.clone(), fragment_path.clone()];
+        let fragment_defines = self.fragment_defines.clone();
+        let result = self.compile(&vertex_path, shaderc::ShaderKind::Vertex, &[], &mut files)
+            .and_then(|vs| Ok((vs, self.compile(&fragment_path, shaderc::ShaderKind::Fragment, &fragment_defines, &mut files)?)));
+
+        // On failure the includes seen so far are still watched, so fixing
+        // an included file triggers the next compile
+        self.watched = Self::watch(files);
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_includes_next_to_the_shader_first() {
+        let dir = std::env::temp_dir().join(format!("hello-wgpu-includes-{}", std::process::id()));
+        let include_dir = dir.join("include");
+        std::fs::create_dir_all(&include_dir).unwrap();
+        for path in [dir.join("common.glsl"), include_dir.join("common.glsl"), include_dir.join("shared.glsl")].iter() {
+            std::fs::write(path, "").unwrap();
+        }
+        let requesting = dir.join("shader.vert");
+        let requesting = requesting.to_str().unwrap();
+
+        let resolve = |requested, include_type| resolve_include(&include_dir, requested, include_type, requesting);
+        assert_eq!(resolve("common.glsl", shaderc::IncludeType::Relative), std::result::Result::Ok(dir.join("common.glsl")));
+        assert_eq!(resolve("common.glsl", shaderc::IncludeType::Standard), std::result::Result::Ok(include_dir.join("common.glsl")));
+        assert_eq!(resolve("shared.glsl", shaderc::IncludeType::Relative), std::result::Result::Ok(include_dir.join("shared.glsl")));
+        assert!(resolve("missing.glsl", shaderc::IncludeType::Relative).is_err());
+    }
+
+    #[test]
+    fn manifest_defines_the_sprite_fragment_permutation() {
+        let manifest = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/shaders/permutations.ron");
+        let permutation = read_permutations(&manifest).unwrap()
+            .into_iter()
+            .find(|p| p.shader == Path::new("shader.frag") && p.name == FRAGMENT_PERMUTATION)
+            .unwrap();
+        assert_eq!(permutation.macro_definitions(), vec![("ALPHA_TEST".to_string(), Some("0.5".to_string()))]);
+    }
+}
@@ -0,0 +1,42 @@
+// #include resolution shared by build.rs and the shader reloader through
+// include!, so shaders compile the same way at build time and at runtime
+
+// Quoted includes are looked up next to the including file first, both kinds
+// fall back to the shared include directory
+fn resolve_include(
+    include_dir: &std::path::Path,
+    requested: &str,
+    include_type: shaderc::IncludeType,
+    requesting: &str,
+) -> std::result::Result<std::path::PathBuf, String> {
+    if let shaderc::IncludeType::Relative = include_type {
+        let path = std::path::Path::new(requesting).parent().unwrap_or(std::path::Path::new("")).join(requested);
+        if path.exists() {
+            return std::result::Result::Ok(path);
+        }
+    }
+
+    let path = include_dir.join(requested);
+    if path.exists() {
+        return std::result::Result::Ok(path);
+    }
+
+    Err(format!("Cannot find {} included from {}", requested, requesting))
+}
+
+// Resolves includes for the compile and records every included file
+fn set_include_callback(
+    options: &mut shaderc::CompileOptions,
+    include_dir: std::path::PathBuf,
+    included: std::rc::Rc<std::cell::RefCell<Vec<std::path::PathBuf>>>,
+) {
+    options.set_include_callback(move |requested, include_type, requesting, _depth| {
+        let path = resolve_include(&include_dir, requested, include_type, requesting)?;
+        let content = std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        included.borrow_mut().push(path.clone());
+        std::result::Result::Ok(shaderc::ResolvedInclude {
+            resolved_name: path.to_string_lossy().into_owned(),
+            content,
+        })
+    });
+}
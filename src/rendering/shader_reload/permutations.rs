@@ -0,0 +1,28 @@
+// The permutation manifest, shared by build.rs and the shader reloader through
+// include! so a reloaded shader gets the same defines as its build
+
+#[derive(serde::Deserialize)]
+struct Permutation {
+    // Relative to src/
+    shader: std::path::PathBuf,
+    name: String,
+    defines: std::collections::BTreeMap<String, String>,
+}
+
+impl Permutation {
+    // A define with an empty value is defined without one
+    fn macro_definitions(&self) -> Vec<(String, Option<String>)> {
+        self.defines
+            .iter()
+            .map(|(name, value)| (name.clone(), if value.is_empty() { None } else { Some(value.clone()) }))
+            .collect()
+    }
+}
+
+fn read_permutations(path: &std::path::Path) -> anyhow::Result<Vec<Permutation>> {
+    use anyhow::Context;
+
+    let src = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    ron::de::from_str(&src).with_context(|| format!("Failed to parse {}", path.display()))
+}
@@ -1,38 +1,67 @@
 // shader.vert
 #version 450
 
+#include "sprite_sheet.glsl"
+
 layout(location=0) in vec3 a_position;
 layout(location=5) in mat4 model_matrix;
 layout(location=9) in uint frame;
+layout(location=10) in uint flags;
+layout(location=11) in vec4 tint;
+// Scale x, scale y, rotation in radians, opacity
+layout(location=12) in vec4 transform;
 
 layout(location=0) out vec2 v_tex_coords;
+layout(location=1) out vec4 v_tint;
 
 layout(set=1, binding=0)
 uniform Uniforms {
     mat4 u_view_proj;
-    vec4 sprite_coordinates[24];
 };
 
 void main() {
     vec2 tex_coords = vec2(0, 0);
+    vec4 rect = sprite_coordinates[frame];
+    vec2 pivot = sprite_pivots[frame].xy;
+
+    // Mirror by swapping opposite edges of the frame
+    if ((flags & FLIP_X) != 0u) {
+        rect = rect.zyxw;
+        pivot.x = 1.0 - pivot.x;
+    }
+    if ((flags & FLIP_Y) != 0u) {
+        rect = rect.xwzy;
+        pivot.y = 1.0 - pivot.y;
+    }
 
     switch(gl_VertexIndex) {
         case 0:
-            tex_coords = vec2(sprite_coordinates[frame].x, sprite_coordinates[frame].w);
+            tex_coords = vec2(rect.x, rect.w);
             break;
         case 1:
-            tex_coords = vec2(sprite_coordinates[frame].z, sprite_coordinates[frame].y);
+            tex_coords = vec2(rect.z, rect.y);
             break;
         case 2:
-            tex_coords = vec2(sprite_coordinates[frame].x, sprite_coordinates[frame].y);
+            tex_coords = vec2(rect.x, rect.y);
             break;
         case 3:
-            tex_coords = vec2(sprite_coordinates[frame].z, sprite_coordinates[frame].w);
+            tex_coords = vec2(rect.z, rect.w);
             break;
     }
 
     v_tex_coords = tex_coords;
+    v_tint = vec4(tint.rgb, tint.a * transform.w);
+
+    // Shift the quad so that the pivot of the frame lands on the instance position.
+    // Pivot is in texture space, so y grows downwards.
+    vec2 pivot_offset = vec2(0.5 - pivot.x, pivot.y - 0.5);
+
+    // Scale and rotate around the pivot, which is now at the origin
+    vec2 local = (a_position.xy + pivot_offset) * transform.xy;
+    float c = cos(transform.z);
+    float s = sin(transform.z);
+    local = vec2(c * local.x - s * local.y, s * local.x + c * local.y);
 
-    gl_Position = u_view_proj * model_matrix * vec4(a_position, 1.0);
+    gl_Position = u_view_proj * model_matrix * vec4(local, a_position.z, 1.0);
     gl_Position.z = model_matrix[1][1];
 }
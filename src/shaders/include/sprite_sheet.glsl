@@ -0,0 +1,15 @@
+// Frames of the sprite sheet bound with the texture, see SheetUniforms
+
+#ifndef MAX_SPRITE_FRAMES
+#define MAX_SPRITE_FRAMES 64
+#endif
+
+// Bits of the instance flags
+const uint FLIP_X = 1u;
+const uint FLIP_Y = 2u;
+
+layout(set=0, binding=2)
+uniform SpriteSheet {
+    vec4 sprite_coordinates[MAX_SPRITE_FRAMES];
+    vec4 sprite_pivots[MAX_SPRITE_FRAMES];
+};
@@ -0,0 +1,381 @@
+use anyhow::*;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::game::{Animation, PlaybackMode};
+use crate::rendering::MAX_SPRITE_FRAMES;
+use crate::texture::{AtlasBuilder, TextureOptions};
+
+mod atlas;
+
+#[derive(Deserialize)]
+struct SpriteSheetDescriptor {
+    // Relative to the descriptor file
+    image: PathBuf,
+    frames: FrameLayout,
+    // Default pivot for frames that don't define their own, (0, 0) is the
+    // top left corner and (1, 1) the bottom right corner of the frame
+    #[serde(default = "default_pivot")]
+    pivot: (f32, f32),
+    #[serde(default)]
+    filter: Filter,
+    animations: Vec<AnimationDescriptor>,
+}
+
+#[derive(Deserialize)]
+enum Filter {
+    // Nearest neighbour, for pixel art
+    Nearest,
+    // Trilinear, for painted art
+    Smooth,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter::Smooth
+    }
+}
+
+impl Filter {
+    fn texture_options(&self) -> TextureOptions {
+        match self {
+            Filter::Nearest => TextureOptions::PIXEL_ART,
+            Filter::Smooth => TextureOptions::SMOOTH,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+enum FrameLayout {
+    // Evenly sized frames, numbered row by row
+    Grid { columns: u32, rows: u32 },
+    // Frame rectangles in pixels
+    Rects(Vec<FrameRect>),
+}
+
+#[derive(Deserialize)]
+struct FrameRect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    #[serde(default)]
+    pivot: Option<(f32, f32)>,
+}
+
+#[derive(Deserialize)]
+struct AnimationDescriptor {
+    name: String,
+    frames: Vec<usize>,
+    frame_ms: u64,
+    // Overrides frame_ms per frame
+    #[serde(default)]
+    frame_timings_ms: Vec<u64>,
+    #[serde(default)]
+    mode: PlaybackMode,
+}
+
+fn default_pivot() -> (f32, f32) {
+    (0.5, 0.5)
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Frame {
+    // Texture coordinates as [left, top, right, bottom]
+    pub rect: [f32; 4],
+    pub pivot: [f32; 2],
+}
+
+/// Index of a sprite sheet in GameState::sprite_sheets
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SpriteSheetHandle(pub usize);
+
+pub struct SpriteSheet {
+    // Descriptor or atlas the sheet was loaded from, None for sheets built at runtime
+    pub path: Option<PathBuf>,
+    pub image_path: PathBuf,
+    // Already decoded image, e.g. an atlas page packed at runtime. Used
+    // instead of loading image_path when set.
+    pub image: Option<image::RgbaImage>,
+    pub texture_options: TextureOptions,
+    pub frames: Vec<Frame>,
+    pub animations: Vec<Animation>,
+}
+
+impl SpriteSheet {
+    pub fn default_path() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("src/trump_run.ron")
+    }
+
+    // Loads either our own RON descriptor or an Aseprite / TexturePacker JSON
+    // atlas, depending on the file extension
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let src = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read sprite sheet {}", path.display()))?;
+
+        let mut sheet = if path.extension().map_or(false, |e| e == "json") {
+            atlas::load(path, &src)
+                .with_context(|| format!("Failed to load atlas {}", path.display()))?
+        } else {
+            Self::load_descriptor(path, &src)?
+        };
+
+        ensure!(
+            sheet.frames.len() <= MAX_SPRITE_FRAMES,
+            "{}: {} frames, at most {} are supported",
+            path.display(),
+            sheet.frames.len(),
+            MAX_SPRITE_FRAMES
+        );
+
+        sheet.path = Some(path.to_path_buf());
+        Ok(sheet)
+    }
+
+    fn load_descriptor(path: &Path, src: &str) -> Result<Self> {
+        let descriptor: SpriteSheetDescriptor = ron::de::from_str(src)
+            .with_context(|| format!("Failed to parse sprite sheet {}", path.display()))?;
+
+        let image_path = path.parent().unwrap_or(Path::new("")).join(&descriptor.image);
+
+        Self::from_descriptor(descriptor, image_path)
+            .with_context(|| format!("Invalid sprite sheet {}", path.display()))
+    }
+
+    fn from_descriptor(descriptor: SpriteSheetDescriptor, image_path: PathBuf) -> Result<Self> {
+        let pivot = [descriptor.pivot.0, descriptor.pivot.1];
+        let texture_options = descriptor.filter.texture_options();
+
+        let frames = match descriptor.frames {
+            FrameLayout::Grid { columns, rows } => {
+                ensure!(columns > 0 && rows > 0, "Grid must have at least one column and row");
+                (0..columns * rows)
+                    .map(|i| Frame {
+                        rect: [
+                            (i % columns) as f32 / columns as f32,
+                            (i / columns) as f32 / rows as f32,
+                            ((i % columns) + 1) as f32 / columns as f32,
+                            ((i / columns) + 1) as f32 / rows as f32,
+                        ],
+                        pivot,
+                    })
+                    .collect::<Vec<_>>()
+            }
+            FrameLayout::Rects(rects) => {
+                let (width, height) = image::image_dimensions(&image_path)
+                    .with_context(|| format!("Failed to read image {}", image_path.display()))?;
+                for (i, r) in rects.iter().enumerate() {
+                    ensure!(r.width > 0 && r.height > 0, "Frame {} is empty", i);
+                    ensure!(
+                        r.x as u64 + r.width as u64 <= width as u64 && r.y as u64 + r.height as u64 <= height as u64,
+                        "Frame {} at ({}, {}) of size {}x{} is outside the {}x{} image",
+                        i, r.x, r.y, r.width, r.height, width, height
+                    );
+                }
+                let (width, height) = (width as f32, height as f32);
+                rects
+                    .iter()
+                    .map(|r| Frame {
+                        rect: [
+                            r.x as f32 / width,
+                            r.y as f32 / height,
+                            (r.x + r.width) as f32 / width,
+                            (r.y + r.height) as f32 / height,
+                        ],
+                        pivot: r.pivot.map(|(x, y)| [x, y]).unwrap_or(pivot),
+                    })
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        let mut animations = Vec::new();
+        for animation in descriptor.animations {
+            ensure!(!animation.frames.is_empty(), "Animation {} has no frames", animation.name);
+            if let Some(frame) = animation.frames.iter().find(|f| **f >= frames.len()) {
+                bail!("Animation {} refers to frame {} but the sheet has {}", animation.name, frame, frames.len());
+            }
+            ensure!(
+                animation.frame_timings_ms.is_empty() || animation.frame_timings_ms.len() == animation.frames.len(),
+                "Animation {} has {} frames but {} frame timings",
+                animation.name,
+                animation.frames.len(),
+                animation.frame_timings_ms.len()
+            );
+            animations.push(Animation {
+                name: animation.name,
+                frames: animation.frames,
+                default_timing: Duration::from_millis(animation.frame_ms),
+                frame_timings: animation.frame_timings_ms.iter().map(|ms| Duration::from_millis(*ms)).collect(),
+                mode: animation.mode,
+            });
+        }
+
+        Ok(Self {
+            path: None,
+            image_path,
+            image: None,
+            texture_options,
+            frames,
+            animations,
+        })
+    }
+
+    pub fn animation(&self, name: &str) -> Option<&Animation> {
+        self.animations.iter().find(|a| a.name == name)
+    }
+
+    // Packs the frames of several sheets into one atlas page so they can be
+    // drawn with a single texture, sampled like the first sheet. Returns the
+    // packed sheet and the index its frames start at for every input sheet.
+    // Animations keep their names, the first sheet wins when names clash.
+    pub fn pack(sheets: &[SpriteSheet], page_size: u32) -> Result<(SpriteSheet, Vec<usize>)> {
+        let texture_options = sheets.first().map_or(TextureOptions::default(), |s| s.texture_options);
+        let mut builder = AtlasBuilder::new(page_size, 2, 1, texture_options);
+        let mut offsets = Vec::new();
+        let mut frame_count = 0;
+        for (i, sheet) in sheets.iter().enumerate() {
+            builder.add_sprite_sheet(&i.to_string(), sheet)?;
+            offsets.push(frame_count);
+            frame_count += sheet.frames.len();
+        }
+
+        let mut pages = builder.build()?.into_sprite_sheets();
+        ensure!(pages.len() == 1, "Sprite sheets need {} atlas pages of {}x{}, not one", pages.len(), page_size, page_size);
+        let mut packed = pages.remove(0);
+
+        for (sheet, offset) in sheets.iter().zip(&offsets) {
+            for animation in &sheet.animations {
+                let mut animation = animation.clone();
+                for frame in &mut animation.frames {
+                    *frame += offset;
+                }
+                packed.animations.push(animation);
+            }
+        }
+
+        Ok((packed, offsets))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes a blank image of the size for the descriptor to measure
+    fn image_file(name: &str, width: u32, height: u32) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("hello-wgpu-{}-{}.png", std::process::id(), name));
+        image::RgbaImage::new(width, height).save(&path).unwrap();
+        path
+    }
+
+    fn from_ron(src: &str, image_path: PathBuf) -> Result<SpriteSheet> {
+        SpriteSheet::from_descriptor(ron::de::from_str(src).unwrap(), image_path)
+    }
+
+    fn grid(animations: &str) -> Result<SpriteSheet> {
+        let src = format!(r#"(
+            image: "grid.png",
+            frames: Grid(columns: 4, rows: 2),
+            animations: [{}],
+        )"#, animations);
+        from_ron(&src, "grid.png".into())
+    }
+
+    fn rects(rects: &str, image_path: PathBuf) -> Result<SpriteSheet> {
+        let src = format!(r#"(
+            image: "rects.png",
+            frames: Rects([{}]),
+            pivot: (0.5, 1.0),
+            animations: [],
+        )"#, rects);
+        from_ron(&src, image_path)
+    }
+
+    fn error(result: Result<SpriteSheet>) -> String {
+        match result {
+            Ok(_) => panic!("Invalid sprite sheet was accepted"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn grid_frames_are_numbered_row_by_row() {
+        let sheet = grid(r#"(name: "walk", frames: [0, 5, 7], frame_ms: 100, mode: PingPong)"#).unwrap();
+        assert_eq!(sheet.frames.len(), 8);
+        assert_eq!(sheet.frames[0].rect, [0.0, 0.0, 0.25, 0.5]);
+        assert_eq!(sheet.frames[5].rect, [0.25, 0.5, 0.5, 1.0]);
+        assert_eq!(sheet.frames[7].pivot, [0.5, 0.5]);
+        assert_eq!(sheet.texture_options.min_filter, wgpu::FilterMode::Linear);
+
+        let walk = sheet.animation("walk").unwrap();
+        assert_eq!(walk.frames, vec![0, 5, 7]);
+        assert_eq!(walk.timing(2), Duration::from_millis(100));
+        assert!(matches!(walk.mode, PlaybackMode::PingPong));
+    }
+
+    #[test]
+    fn per_frame_timings_override_the_default() {
+        let sheet = grid(r#"(name: "walk", frames: [0, 1], frame_ms: 100, frame_timings_ms: [50, 250])"#).unwrap();
+        let walk = sheet.animation("walk").unwrap();
+        assert_eq!(walk.timing(0), Duration::from_millis(50));
+        assert_eq!(walk.timing(1), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn rect_frames_are_normalized_with_their_own_pivots() {
+        let image = image_file("rects", 64, 32);
+        let sheet = rects(
+            "(x: 0, y: 0, width: 16, height: 32), (x: 16, y: 8, width: 48, height: 24, pivot: Some((0.25, 0.75)))",
+            image,
+        ).unwrap();
+        assert_eq!(sheet.frames[0].rect, [0.0, 0.0, 0.25, 1.0]);
+        assert_eq!(sheet.frames[0].pivot, [0.5, 1.0]);
+        assert_eq!(sheet.frames[1].rect, [0.25, 0.25, 1.0, 1.0]);
+        assert_eq!(sheet.frames[1].pivot, [0.25, 0.75]);
+    }
+
+    #[test]
+    fn rejects_rect_frames_outside_the_image() {
+        let image = image_file("outside", 64, 32);
+        let message = error(rects("(x: 0, y: 0, width: 16, height: 16), (x: 56, y: 0, width: 16, height: 16)", image.clone()));
+        assert!(message.contains("Frame 1"), "{}", message);
+        let message = error(rects("(x: 0, y: 20, width: 16, height: 16)", image.clone()));
+        assert!(message.contains("outside the 64x32 image"), "{}", message);
+        let message = error(rects("(x: 4294967295, y: 0, width: 1, height: 1)", image.clone()));
+        assert!(message.contains("outside"), "{}", message);
+        let message = error(rects("(x: 0, y: 0, width: 0, height: 16)", image));
+        assert!(message.contains("Frame 0 is empty"), "{}", message);
+    }
+
+    #[test]
+    fn rejects_rect_frames_without_an_image() {
+        let message = error(rects("(x: 0, y: 0, width: 16, height: 16)", "missing.png".into()));
+        assert!(message.contains("Failed to read image missing.png"), "{}", message);
+    }
+
+    #[test]
+    fn rejects_an_empty_grid() {
+        let src = r#"(image: "grid.png", frames: Grid(columns: 0, rows: 2), animations: [])"#;
+        assert!(error(from_ron(src, "grid.png".into())).contains("at least one column"));
+    }
+
+    #[test]
+    fn rejects_animations_with_frames_out_of_range() {
+        let message = error(grid(r#"(name: "walk", frames: [0, 8], frame_ms: 100)"#));
+        assert!(message.contains("refers to frame 8 but the sheet has 8"), "{}", message);
+    }
+
+    #[test]
+    fn rejects_animations_without_frames() {
+        let message = error(grid(r#"(name: "walk", frames: [], frame_ms: 100)"#));
+        assert!(message.contains("walk has no frames"), "{}", message);
+    }
+
+    #[test]
+    fn rejects_a_mismatched_frame_timing_count() {
+        let message = error(grid(r#"(name: "walk", frames: [0, 1, 2], frame_ms: 100, frame_timings_ms: [50, 50])"#));
+        assert!(message.contains("3 frames but 2 frame timings"), "{}", message);
+    }
+}
@@ -0,0 +1,357 @@
+// Loader for the JSON atlases exported by Aseprite and TexturePacker. Both
+// write the same "frames" + "meta" layout, either as a hash keyed by file name
+// or as an array. Aseprite adds frame durations and tags, TexturePacker adds
+// pivots.
+
+use anyhow::*;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::game::{Animation, PlaybackMode};
+use super::{Filter, Frame, SpriteSheet};
+
+// TexturePacker has no frame timings
+const DEFAULT_FRAME_MS: u64 = 100;
+
+#[derive(Deserialize)]
+struct Atlas {
+    frames: AtlasFrames,
+    meta: Meta,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AtlasFrames {
+    // Relies on serde_json preserving the order of the keys
+    Hash(serde_json::Map<String, serde_json::Value>),
+    Array(Vec<NamedFrame>),
+}
+
+#[derive(Deserialize)]
+struct NamedFrame {
+    filename: String,
+    #[serde(flatten)]
+    frame: AtlasFrame,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AtlasFrame {
+    frame: Rect,
+    #[serde(default)]
+    rotated: bool,
+    #[serde(default)]
+    trimmed: bool,
+    sprite_source_size: Option<Rect>,
+    source_size: Option<Size>,
+    pivot: Option<Point>,
+    duration: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct Rect {
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32,
+}
+
+#[derive(Deserialize)]
+struct Size {
+    w: u32,
+    h: u32,
+}
+
+#[derive(Deserialize)]
+struct Point {
+    x: f32,
+    y: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Meta {
+    #[serde(default)]
+    app: String,
+    image: PathBuf,
+    size: Size,
+    #[serde(default)]
+    frame_tags: Vec<FrameTag>,
+    // Not written by the exporters. Aseprite exports default to Nearest as
+    // they are pixel art, everything else to Smooth.
+    filter: Option<Filter>,
+}
+
+#[derive(Deserialize)]
+struct FrameTag {
+    name: String,
+    from: usize,
+    to: usize,
+    #[serde(default)]
+    direction: String,
+}
+
+impl FrameTag {
+    fn mode(&self) -> PlaybackMode {
+        match self.direction.as_str() {
+            "reverse" => PlaybackMode::Reverse,
+            "pingpong" | "pingpong_reverse" => PlaybackMode::PingPong,
+            _ => PlaybackMode::Loop,
+        }
+    }
+}
+
+pub fn load(path: &Path, src: &str) -> Result<SpriteSheet> {
+    let atlas: Atlas = serde_json::from_str(src)?;
+
+    let named_frames = match atlas.frames {
+        AtlasFrames::Array(frames) => frames,
+        AtlasFrames::Hash(frames) => frames
+            .into_iter()
+            .map(|(filename, value)| {
+                let frame = serde_json::from_value(value)
+                    .with_context(|| format!("Invalid frame {}", filename))?;
+                Ok(NamedFrame { filename, frame })
+            })
+            .collect::<Result<Vec<_>>>()?,
+    };
+
+    let (width, height) = (atlas.meta.size.w as f32, atlas.meta.size.h as f32);
+
+    let mut frames = Vec::new();
+    let mut durations = Vec::new();
+    for named in &named_frames {
+        let f = &named.frame;
+        ensure!(!f.rotated, "Frame {} is rotated, rotated frames are not supported", named.filename);
+
+        frames.push(Frame {
+            rect: [
+                f.frame.x as f32 / width,
+                f.frame.y as f32 / height,
+                (f.frame.x + f.frame.w) as f32 / width,
+                (f.frame.y + f.frame.h) as f32 / height,
+            ],
+            pivot: pivot(f),
+        });
+        durations.push(Duration::from_millis(f.duration.unwrap_or(DEFAULT_FRAME_MS)));
+    }
+
+    let animations = if atlas.meta.frame_tags.is_empty() {
+        animations_from_names(&named_frames, &durations)
+    } else {
+        let mut animations = Vec::new();
+        for tag in &atlas.meta.frame_tags {
+            ensure!(
+                tag.from <= tag.to && tag.to < frames.len(),
+                "Tag {} covers frames {}..={} but the atlas has {}",
+                tag.name,
+                tag.from,
+                tag.to,
+                frames.len()
+            );
+            let mut tag_frames = (tag.from..=tag.to).collect::<Vec<_>>();
+            let mut frame_timings = durations[tag.from..=tag.to].to_vec();
+            // PingPong starts forward from the first frame, so a ping-pong
+            // starting backwards from the last frame is the same frames reversed
+            if tag.direction == "pingpong_reverse" {
+                tag_frames.reverse();
+                frame_timings.reverse();
+            }
+            animations.push(Animation {
+                name: tag.name.clone(),
+                frames: tag_frames,
+                default_timing: Duration::from_millis(DEFAULT_FRAME_MS),
+                frame_timings,
+                mode: tag.mode(),
+            });
+        }
+        animations
+    };
+
+    Ok(SpriteSheet {
+        path: None,
+        image_path: path.parent().unwrap_or(Path::new("")).join(&atlas.meta.image),
+        image: None,
+        texture_options: atlas.meta.filter
+            .unwrap_or(if atlas.meta.app.contains("aseprite") { Filter::Nearest } else { Filter::Smooth })
+            .texture_options(),
+        frames,
+        animations,
+    })
+}
+
+// Pivot relative to the packed rectangle. Trimmed frames have had their
+// transparent border cut off, so the pivot is moved to keep the sprite
+// anchored at the same point of the original image.
+fn pivot(f: &AtlasFrame) -> [f32; 2] {
+    let pivot = f.pivot.as_ref().map(|p| (p.x, p.y)).unwrap_or((0.5, 0.5));
+
+    match (f.trimmed, &f.sprite_source_size, &f.source_size) {
+        (true, Some(trimmed), Some(source)) if trimmed.w > 0 && trimmed.h > 0 => [
+            (pivot.0 * source.w as f32 - trimmed.x as f32) / trimmed.w as f32,
+            (pivot.1 * source.h as f32 - trimmed.y as f32) / trimmed.h as f32,
+        ],
+        _ => [pivot.0, pivot.1],
+    }
+}
+
+// Without tags, frames named e.g. "walk_S_0.png", "walk_S_1.png" are grouped
+// into an animation called "walk_S", in the order they appear in the atlas
+fn animations_from_names(named_frames: &[NamedFrame], durations: &[Duration]) -> Vec<Animation> {
+    let mut animations: Vec<Animation> = Vec::new();
+
+    for (i, named) in named_frames.iter().enumerate() {
+        let stem = match named.filename.rfind('.') {
+            Some(dot) => &named.filename[..dot],
+            None => &named.filename,
+        };
+        let name = stem
+            .trim_end_matches(|c: char| c.is_ascii_digit())
+            .trim_end_matches(|c: char| c == '_' || c == '-' || c == ' ' || c == '/');
+        let name = if name.is_empty() { stem } else { name };
+
+        match animations.iter_mut().find(|a| a.name == name) {
+            Some(animation) => {
+                animation.frames.push(i);
+                animation.frame_timings.push(durations[i]);
+            }
+            None => animations.push(Animation {
+                name: name.to_string(),
+                frames: vec![i],
+                default_timing: Duration::from_millis(DEFAULT_FRAME_MS),
+                frame_timings: vec![durations[i]],
+                mode: PlaybackMode::Loop,
+            }),
+        }
+    }
+
+    animations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ASEPRITE_HASH: &str = r#"{
+        "frames": {
+            "hero 0.aseprite": {
+                "frame": { "x": 0, "y": 0, "w": 16, "h": 16 },
+                "rotated": false,
+                "trimmed": false,
+                "spriteSourceSize": { "x": 0, "y": 0, "w": 16, "h": 16 },
+                "sourceSize": { "w": 16, "h": 16 },
+                "duration": 100
+            },
+            "hero 1.aseprite": {
+                "frame": { "x": 16, "y": 0, "w": 16, "h": 16 },
+                "rotated": false,
+                "trimmed": false,
+                "spriteSourceSize": { "x": 0, "y": 0, "w": 16, "h": 16 },
+                "sourceSize": { "w": 16, "h": 16 },
+                "duration": 150
+            },
+            "hero 2.aseprite": {
+                "frame": { "x": 32, "y": 0, "w": 16, "h": 16 },
+                "rotated": false,
+                "trimmed": false,
+                "spriteSourceSize": { "x": 0, "y": 0, "w": 16, "h": 16 },
+                "sourceSize": { "w": 16, "h": 16 },
+                "duration": 200
+            }
+        },
+        "meta": {
+            "app": "https://www.aseprite.org/",
+            "image": "hero.png",
+            "size": { "w": 64, "h": 16 },
+            "frameTags": [
+                { "name": "walk", "from": 0, "to": 2, "direction": "forward" },
+                { "name": "back", "from": 0, "to": 1, "direction": "reverse" },
+                { "name": "bounce", "from": 0, "to": 2, "direction": "pingpong_reverse" }
+            ]
+        }
+    }"#;
+
+    const TEXTURE_PACKER_ARRAY: &str = r#"{
+        "frames": [
+            {
+                "filename": "walk_0.png",
+                "frame": { "x": 0, "y": 0, "w": 10, "h": 20 },
+                "rotated": false,
+                "trimmed": true,
+                "spriteSourceSize": { "x": 2, "y": 0, "w": 10, "h": 20 },
+                "sourceSize": { "w": 20, "h": 20 },
+                "pivot": { "x": 0.5, "y": 1.0 }
+            },
+            {
+                "filename": "walk_1.png",
+                "frame": { "x": 10, "y": 0, "w": 10, "h": 20 },
+                "rotated": false,
+                "trimmed": false,
+                "spriteSourceSize": { "x": 0, "y": 0, "w": 10, "h": 20 },
+                "sourceSize": { "w": 10, "h": 20 },
+                "pivot": { "x": 0.5, "y": 1.0 }
+            }
+        ],
+        "meta": {
+            "image": "walk.png",
+            "size": { "w": 20, "h": 20 }
+        }
+    }"#;
+
+    fn animation<'a>(sheet: &'a SpriteSheet, name: &str) -> &'a Animation {
+        sheet.animations.iter().find(|a| a.name == name).unwrap()
+    }
+
+    #[test]
+    fn loads_aseprite_hash_with_tags() {
+        let sheet = load(Path::new("sheets/hero.json"), ASEPRITE_HASH).unwrap();
+
+        assert_eq!(sheet.image_path, Path::new("sheets/hero.png"));
+        assert_eq!(sheet.texture_options.mag_filter, wgpu::FilterMode::Nearest);
+        assert_eq!(sheet.frames.len(), 3);
+        assert_eq!(sheet.frames[1].rect, [0.25, 0.0, 0.5, 1.0]);
+
+        let walk = animation(&sheet, "walk");
+        assert_eq!(walk.frames, vec![0, 1, 2]);
+        assert_eq!(walk.frame_timings, vec![
+            Duration::from_millis(100),
+            Duration::from_millis(150),
+            Duration::from_millis(200),
+        ]);
+        assert_eq!(walk.mode, PlaybackMode::Loop);
+
+        assert_eq!(animation(&sheet, "back").mode, PlaybackMode::Reverse);
+
+        let bounce = animation(&sheet, "bounce");
+        assert_eq!(bounce.mode, PlaybackMode::PingPong);
+        assert_eq!(bounce.frames, vec![2, 1, 0]);
+        assert_eq!(bounce.frame_timings[0], Duration::from_millis(200));
+    }
+
+    #[test]
+    fn loads_texture_packer_array_with_trimmed_pivot() {
+        let sheet = load(Path::new("walk.json"), TEXTURE_PACKER_ARRAY).unwrap();
+
+        assert_eq!(sheet.frames.len(), 2);
+        assert_eq!(sheet.texture_options.mag_filter, wgpu::FilterMode::Linear);
+        assert_eq!(sheet.frames[0].rect, [0.0, 0.0, 0.5, 1.0]);
+        // The pivot at the bottom center of the 20 pixel wide source lands at
+        // x = 8 in the frame that had 2 pixels trimmed off the left
+        assert_eq!(sheet.frames[0].pivot, [0.8, 1.0]);
+        assert_eq!(sheet.frames[1].pivot, [0.5, 1.0]);
+
+        // Without tags the frames are grouped by name
+        assert_eq!(sheet.animations.len(), 1);
+        let walk = animation(&sheet, "walk");
+        assert_eq!(walk.frames, vec![0, 1]);
+        assert_eq!(walk.frame_timings, vec![Duration::from_millis(DEFAULT_FRAME_MS); 2]);
+    }
+
+    #[test]
+    fn filter_overrides_the_default() {
+        let src = TEXTURE_PACKER_ARRAY.replace(r#""image": "walk.png","#, r#""image": "walk.png", "filter": "Nearest","#);
+        let sheet = load(Path::new("walk.json"), &src).unwrap();
+        assert_eq!(sheet.texture_options.mag_filter, wgpu::FilterMode::Nearest);
+    }
+}
@@ -1,5 +1,63 @@
 use anyhow::*;
 use image::GenericImageView;
+use std::path::{Path, PathBuf};
+
+use crate::rendering::MAX_SPRITE_FRAMES;
+use crate::sprite_sheet::{Frame, SpriteSheet};
+
+/// How a texture is stored and sampled
+#[derive(Clone, Copy, Debug)]
+pub struct TextureOptions {
+    pub mag_filter: wgpu::FilterMode,
+    pub min_filter: wgpu::FilterMode,
+    pub mipmap_filter: wgpu::FilterMode,
+    pub address_mode: wgpu::AddressMode,
+    // Colour data is sRGB encoded, data such as normal maps is linear
+    pub srgb: bool,
+    // Most mip levels to generate, 1 turns mipmaps off. The chain ends at
+    // 1x1 either way.
+    pub mip_levels: u32,
+}
+
+impl TextureOptions {
+    // Hard pixel edges when zoomed in, mipmaps against shimmering when zoomed out
+    pub const PIXEL_ART: TextureOptions = TextureOptions {
+        mag_filter: wgpu::FilterMode::Nearest,
+        min_filter: wgpu::FilterMode::Nearest,
+        mipmap_filter: wgpu::FilterMode::Nearest,
+        address_mode: wgpu::AddressMode::ClampToEdge,
+        srgb: true,
+        mip_levels: u32::MAX,
+    };
+
+    // Trilinear filtering
+    pub const SMOOTH: TextureOptions = TextureOptions {
+        mag_filter: wgpu::FilterMode::Linear,
+        min_filter: wgpu::FilterMode::Linear,
+        mipmap_filter: wgpu::FilterMode::Linear,
+        address_mode: wgpu::AddressMode::ClampToEdge,
+        srgb: true,
+        mip_levels: u32::MAX,
+    };
+
+    fn format(&self) -> wgpu::TextureFormat {
+        if self.srgb {
+            wgpu::TextureFormat::Rgba8UnormSrgb
+        } else {
+            wgpu::TextureFormat::Rgba8Unorm
+        }
+    }
+}
+
+impl Default for TextureOptions {
+    fn default() -> Self {
+        Self::SMOOTH
+    }
+}
+
+// wgpu can't query the texture size limit yet, this is the WebGPU default
+// every backend supports
+pub const MAX_TEXTURE_DIMENSION: u32 = 8192;
 
 pub struct Texture {
     pub texture: wgpu::Texture,
@@ -10,10 +68,10 @@ pub struct Texture {
 impl Texture {
     pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
     
-    pub fn create_depth_texture(device: &wgpu::Device, sc_desc: &wgpu::SwapChainDescriptor, label: &str) -> Self {
+    pub fn create_depth_texture(device: &wgpu::Device, width: u32, height: u32, label: &str) -> Self {
         let size = wgpu::Extent3d { 
-            width: sc_desc.width,
-            height: sc_desc.height,
+            width,
+            height,
             depth: 1,
         };
         let desc = wgpu::TextureDescriptor {
@@ -47,24 +105,32 @@ impl Texture {
         Self { texture, view, sampler }
     }
 
-    pub fn from_bytes(
+    pub fn from_path<P: AsRef<Path>>(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
-        bytes: &[u8],
-        label: &str,
+        path: P,
+        options: &TextureOptions,
     ) -> Result<Self> {
-        let img = image::load_from_memory(bytes)?;
-        Self::from_image(device, queue, &img, Some(label))
+        let path = path.as_ref();
+        let img = image::open(path)
+            .with_context(|| format!("Failed to load texture {}", path.display()))?;
+        let label = path.to_string_lossy();
+        Self::from_image(device, queue, &img, Some(&label), options)
+            .with_context(|| format!("Failed to create texture from {}", path.display()))
     }
 
+    // Accepts any pixel format, everything is converted to 8 bit RGBA
     pub fn from_image(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         img: &image::DynamicImage,
         label: Option<&str>,
+        options: &TextureOptions,
     ) -> Result<Self> {
-        let rgba = img.as_rgba8().unwrap();
         let dimensions = img.dimensions();
+        let rgba = Self::to_rgba8(img)?;
+
+        let mip_levels = generate_mipmaps(rgba.into_owned(), options.mip_levels);
 
         let size = wgpu::Extent3d {
             width: dimensions.0,
@@ -74,36 +140,42 @@ impl Texture {
         let texture = device.create_texture(&wgpu::TextureDescriptor {
             label,
             size,
-            mip_level_count: 1,
+            mip_level_count: mip_levels.len() as u32,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            format: options.format(),
             usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
         });
 
-        queue.write_texture(
-            wgpu::TextureCopyView {
-                texture: &texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-            },
-            rgba,
-            wgpu::TextureDataLayout {
-                offset: 0,
-                bytes_per_row: 4 * dimensions.0,
-                rows_per_image: dimensions.1,
-            },
-            size,
-        );
+        for (level, mip) in mip_levels.iter().enumerate() {
+            queue.write_texture(
+                wgpu::TextureCopyView {
+                    texture: &texture,
+                    mip_level: level as u32,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                mip,
+                wgpu::TextureDataLayout {
+                    offset: 0,
+                    bytes_per_row: 4 * mip.width(),
+                    rows_per_image: mip.height(),
+                },
+                wgpu::Extent3d {
+                    width: mip.width(),
+                    height: mip.height(),
+                    depth: 1,
+                },
+            );
+        }
 
         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
         let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
-            address_mode_u: wgpu::AddressMode::ClampToEdge,
-            address_mode_v: wgpu::AddressMode::ClampToEdge,
-            address_mode_w: wgpu::AddressMode::ClampToEdge,
-            mag_filter: wgpu::FilterMode::Linear,
-            min_filter: wgpu::FilterMode::Nearest,
-            mipmap_filter: wgpu::FilterMode::Nearest,
+            address_mode_u: options.address_mode,
+            address_mode_v: options.address_mode,
+            address_mode_w: options.address_mode,
+            mag_filter: options.mag_filter,
+            min_filter: options.min_filter,
+            mipmap_filter: options.mipmap_filter,
             ..Default::default()
         });
 
@@ -113,4 +185,448 @@ impl Texture {
             sampler,
         })
     }
+
+    // Checks the size and converts other pixel formats to 8 bit RGBA, borrowing
+    // images that already are
+    fn to_rgba8(img: &image::DynamicImage) -> Result<std::borrow::Cow<'_, image::RgbaImage>> {
+        let (width, height) = img.dimensions();
+        ensure!(width > 0 && height > 0, "Image is empty ({}x{})", width, height);
+        ensure!(
+            width <= MAX_TEXTURE_DIMENSION && height <= MAX_TEXTURE_DIMENSION,
+            "{}x{} {:?} image exceeds the maximum texture size of {}x{}",
+            width,
+            height,
+            img.color(),
+            MAX_TEXTURE_DIMENSION,
+            MAX_TEXTURE_DIMENSION
+        );
+
+        Ok(match img.as_rgba8() {
+            Some(rgba) => std::borrow::Cow::Borrowed(rgba),
+            None => std::borrow::Cow::Owned(img.to_rgba8()),
+        })
+    }
+}
+
+// Mip chain down to 1x1 or max_levels, each level a filtered half of the
+// previous one. The filtering happens on the stored values, i.e. in sRGB for
+// colour textures, which is slightly too dark but good enough for sprites.
+fn generate_mipmaps(img: image::RgbaImage, max_levels: u32) -> Vec<image::RgbaImage> {
+    let mut levels = vec![img];
+    while levels.len() < max_levels as usize {
+        let last = levels.last().unwrap();
+        if last.width() == 1 && last.height() == 1 {
+            break;
+        }
+        let next = downsample(last);
+        levels.push(next);
+    }
+    levels
+}
+
+// Averages 2x2 blocks, so a texel of level n covers exactly the 2^n pixels
+// of its block and nothing next to it. Odd sizes drop the last row or column.
+fn downsample(img: &image::RgbaImage) -> image::RgbaImage {
+    let (width, height) = ((img.width() / 2).max(1), (img.height() / 2).max(1));
+    image::RgbaImage::from_fn(width, height, |x, y| {
+        let mut sum = [0u32; 4];
+        for (sx, sy) in [(0, 0), (1, 0), (0, 1), (1, 1)].iter() {
+            let sx = (2 * x + sx).min(img.width() - 1);
+            let sy = (2 * y + sy).min(img.height() - 1);
+            for (channel, value) in sum.iter_mut().zip(img.get_pixel(sx, sy).0.iter()) {
+                *channel += *value as u32;
+            }
+        }
+        image::Rgba([
+            ((sum[0] + 2) / 4) as u8,
+            ((sum[1] + 2) / 4) as u8,
+            ((sum[2] + 2) / 4) as u8,
+            ((sum[3] + 2) / 4) as u8,
+        ])
+    })
+}
+
+/// Where a packed image ended up in an Atlas
+pub struct AtlasRegion {
+    pub page: usize,
+    // Index of the frame within the sprite sheet made from the page. Images
+    // on the same page are numbered in the order they were added.
+    pub index: usize,
+    pub frame: Frame,
+}
+
+pub struct Atlas {
+    pub pages: Vec<image::RgbaImage>,
+    pub texture_options: TextureOptions,
+    // In the order the images were added
+    pub regions: Vec<AtlasRegion>,
+}
+
+impl Atlas {
+    // One sprite sheet per page, frames in the order of AtlasRegion::index
+    pub fn into_sprite_sheets(self) -> Vec<SpriteSheet> {
+        let mut frames = vec![Vec::new(); self.pages.len()];
+        for region in &self.regions {
+            frames[region.page].push((region.index, region.frame));
+        }
+
+        let texture_options = self.texture_options;
+        self.pages
+            .into_iter()
+            .zip(frames)
+            .enumerate()
+            .map(|(i, (page, mut frames))| {
+                frames.sort_by_key(|(index, _)| *index);
+                SpriteSheet {
+                    path: None,
+                    image_path: PathBuf::from(format!("atlas page {}", i)),
+                    image: Some(page),
+                    texture_options,
+                    frames: frames.into_iter().map(|(_, frame)| frame).collect(),
+                    animations: Vec::new(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Packs loose images into square atlas pages using shelf packing. Every
+/// image gets its edge pixels repeated `extrude` times around it and
+/// `padding` empty pixels between neighbours so filtering doesn't bleed.
+pub struct AtlasBuilder {
+    page_size: u32,
+    padding: u32,
+    extrude: u32,
+    // Used for the sheets made from the pages
+    texture_options: TextureOptions,
+    // Name, image and pivot of every frame
+    images: Vec<(String, image::RgbaImage, [f32; 2])>,
+}
+
+// Fill state of the page currently being packed
+struct Shelf {
+    x: u32,
+    y: u32,
+    height: u32,
+    count: usize,
+}
+
+impl AtlasBuilder {
+    pub fn new(page_size: u32, padding: u32, extrude: u32, texture_options: TextureOptions) -> Self {
+        Self {
+            page_size,
+            padding,
+            extrude,
+            texture_options,
+            images: Vec::new(),
+        }
+    }
+
+    // The name is only used in errors
+    pub fn add_image(&mut self, name: &str, img: image::RgbaImage, pivot: [f32; 2]) {
+        self.images.push((name.to_string(), img, pivot));
+    }
+
+    // Adds every frame of the sheet with its pivot, named e.g. "hero/3" for frame 3
+    pub fn add_sprite_sheet(&mut self, name: &str, sheet: &SpriteSheet) -> Result<()> {
+        let loaded;
+        let img = match &sheet.image {
+            Some(img) => img,
+            None => {
+                loaded = image::open(&sheet.image_path)
+                    .with_context(|| format!("Failed to load {}", sheet.image_path.display()))?
+                    .to_rgba8();
+                &loaded
+            }
+        };
+
+        let (width, height) = (img.width() as f32, img.height() as f32);
+        for (i, frame) in sheet.frames.iter().enumerate() {
+            let x = (frame.rect[0] * width).round() as u32;
+            let y = (frame.rect[1] * height).round() as u32;
+            let w = (frame.rect[2] * width).round() as u32 - x;
+            let h = (frame.rect[3] * height).round() as u32 - y;
+            ensure!(w > 0 && h > 0, "Frame {} of {} is empty", i, name);
+            let cropped = image::imageops::crop_imm(img, x, y, w, h).to_image();
+            self.add_image(&format!("{}/{}", name, i), cropped, frame.pivot);
+        }
+        Ok(())
+    }
+
+    // A texel of mip level n covers a block of 2^n pixels of the page. At the
+    // edge of a frame the block reaches over its extrusion and the padding,
+    // the levels where it reaches the next frame would blend the two.
+    fn max_mip_levels(padding: u32, extrude: u32) -> u32 {
+        let gutter = padding + extrude + 1;
+        32 - gutter.leading_zeros()
+    }
+
+    pub fn build(self) -> Result<Atlas> {
+        let size = self.page_size;
+        let (padding, extrude) = (self.padding, self.extrude);
+
+        // Tallest first keeps the shelves tight
+        let mut order = (0..self.images.len()).collect::<Vec<_>>();
+        order.sort_by_key(|i| std::cmp::Reverse(self.images[*i].1.height()));
+
+        let mut pages: Vec<image::RgbaImage> = Vec::new();
+        let mut shelf = Shelf { x: 0, y: 0, height: 0, count: 0 };
+        let mut placements = vec![(0, 0, 0); self.images.len()];
+
+        for i in order {
+            let (name, img, _) = &self.images[i];
+            let (width, height) = (img.width() + 2 * extrude, img.height() + 2 * extrude);
+            ensure!(
+                width + 2 * padding <= size && height + 2 * padding <= size,
+                "{} ({}x{}) does not fit into a {}x{} atlas page",
+                name,
+                img.width(),
+                img.height(),
+                size,
+                size
+            );
+
+            if !pages.is_empty() && shelf.x + width + padding > size {
+                shelf = Shelf {
+                    x: padding,
+                    y: shelf.y + shelf.height + padding,
+                    height: 0,
+                    count: shelf.count,
+                };
+            }
+            // The frames of a page have to fit into the uniforms of one sheet
+            if pages.is_empty()
+                || shelf.y + height + padding > size
+                || shelf.count == MAX_SPRITE_FRAMES
+            {
+                pages.push(image::RgbaImage::new(size, size));
+                shelf = Shelf { x: padding, y: padding, height: 0, count: 0 };
+            }
+
+            let page = pages.last_mut().unwrap();
+            for y in 0..height {
+                for x in 0..width {
+                    // Clamping the source coordinates repeats the edges into the extrusion
+                    let src_x = (x as i64 - extrude as i64).max(0).min(img.width() as i64 - 1) as u32;
+                    let src_y = (y as i64 - extrude as i64).max(0).min(img.height() as i64 - 1) as u32;
+                    page.put_pixel(shelf.x + x, shelf.y + y, *img.get_pixel(src_x, src_y));
+                }
+            }
+
+            placements[i] = (pages.len() - 1, shelf.x + extrude, shelf.y + extrude);
+            shelf.x += width + padding;
+            shelf.height = shelf.height.max(height);
+            shelf.count += 1;
+        }
+
+        // Frames of a page keep the order the images were added in, so frames
+        // added together stay together in the sheet made from the page
+        let mut page_counts = vec![0; pages.len()];
+        let regions = self.images
+            .iter()
+            .zip(placements)
+            .map(|((_, img, pivot), (page, x, y))| {
+                let index = page_counts[page];
+                page_counts[page] += 1;
+                AtlasRegion {
+                    page,
+                    index,
+                    frame: Frame {
+                        rect: [
+                            x as f32 / size as f32,
+                            y as f32 / size as f32,
+                            (x + img.width()) as f32 / size as f32,
+                            (y + img.height()) as f32 / size as f32,
+                        ],
+                        pivot: *pivot,
+                    },
+                }
+            })
+            .collect();
+
+        let mut texture_options = self.texture_options;
+        texture_options.mip_levels = texture_options.mip_levels.min(Self::max_mip_levels(padding, extrude));
+
+        Ok(Atlas {
+            pages,
+            texture_options,
+            regions,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CENTER: [f32; 2] = [0.5, 0.5];
+
+    fn solid(width: u32, height: u32, color: [u8; 4]) -> image::RgbaImage {
+        image::RgbaImage::from_pixel(width, height, image::Rgba(color))
+    }
+
+    #[test]
+    fn converts_any_pixel_format_to_rgba8() {
+        let rgb = image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(3, 2, image::Rgb([10, 20, 30])));
+        let converted = Texture::to_rgba8(&rgb).unwrap();
+        assert_eq!(converted.dimensions(), (3, 2));
+        assert_eq!(converted.get_pixel(0, 0).0, [10, 20, 30, 255]);
+
+        let grey = image::DynamicImage::ImageLuma8(image::GrayImage::from_pixel(2, 2, image::Luma([128])));
+        assert_eq!(Texture::to_rgba8(&grey).unwrap().get_pixel(1, 1).0, [128, 128, 128, 255]);
+
+        let deep = image::DynamicImage::ImageRgba16(image::ImageBuffer::from_pixel(2, 2, image::Rgba([65535u16, 0, 32896, 65535])));
+        assert_eq!(Texture::to_rgba8(&deep).unwrap().get_pixel(0, 1).0, [255, 0, 128, 255]);
+
+        let rgba = image::DynamicImage::ImageRgba8(solid(1, 1, [1, 2, 3, 4]));
+        assert!(matches!(Texture::to_rgba8(&rgba).unwrap(), std::borrow::Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn rejects_empty_and_oversized_images() {
+        let empty = image::DynamicImage::ImageRgba8(image::RgbaImage::new(0, 4));
+        assert!(Texture::to_rgba8(&empty).is_err());
+
+        let wide = image::DynamicImage::ImageLuma8(image::GrayImage::new(MAX_TEXTURE_DIMENSION + 1, 1));
+        assert!(Texture::to_rgba8(&wide).is_err());
+    }
+
+    #[test]
+    fn mip_chain_halves_down_to_one_pixel() {
+        let levels = generate_mipmaps(solid(8, 4, [200, 100, 50, 255]), u32::MAX);
+        let sizes = levels.iter().map(|l| l.dimensions()).collect::<Vec<_>>();
+        assert_eq!(sizes, vec![(8, 4), (4, 2), (2, 1), (1, 1)]);
+        assert!(levels.iter().all(|l| l.pixels().all(|p| p.0 == [200, 100, 50, 255])));
+
+        let checker = image::RgbaImage::from_fn(2, 2, |x, y| image::Rgba(if (x + y) % 2 == 0 { [255; 4] } else { [0; 4] }));
+        assert_eq!(generate_mipmaps(checker, 2)[1].get_pixel(0, 0).0, [128; 4]);
+
+        assert_eq!(generate_mipmaps(solid(8, 4, [0; 4]), 2).len(), 2);
+        assert_eq!(generate_mipmaps(solid(8, 4, [0; 4]), 1).len(), 1);
+    }
+
+    #[test]
+    fn atlas_mips_stop_before_reaching_the_next_frame() {
+        assert_eq!(AtlasBuilder::max_mip_levels(0, 0), 1);
+        assert_eq!(AtlasBuilder::max_mip_levels(1, 0), 2);
+        assert_eq!(AtlasBuilder::max_mip_levels(2, 1), 3);
+        assert_eq!(AtlasBuilder::max_mip_levels(4, 3), 4);
+
+        let mut builder = AtlasBuilder::new(32, 2, 1, TextureOptions::SMOOTH);
+        builder.add_image("red", solid(8, 8, [255, 0, 0, 255]), CENTER);
+        builder.add_image("blue", solid(8, 8, [0, 0, 255, 255]), CENTER);
+        let atlas = builder.build().unwrap();
+        assert_eq!(atlas.texture_options.mip_levels, 3);
+        let red = atlas.regions[0].frame.rect;
+
+        // Texels with their center inside the red frame
+        let bleeds = |levels: &[image::RgbaImage]| {
+            levels.iter().any(|level| {
+                let texel = 32.0 / level.width() as f32;
+                level.enumerate_pixels().any(|(x, y, pixel)| {
+                    let center = ((x as f32 + 0.5) * texel / 32.0, (y as f32 + 0.5) * texel / 32.0);
+                    let inside = center.0 > red[0] && center.0 < red[2] && center.1 > red[1] && center.1 < red[3];
+                    inside && pixel.0[2] > 0
+                })
+            })
+        };
+        let page = atlas.pages[0].clone();
+        assert!(!bleeds(&generate_mipmaps(page.clone(), atlas.texture_options.mip_levels)));
+        assert!(bleeds(&generate_mipmaps(page, u32::MAX)));
+    }
+
+    #[test]
+    fn pads_and_extrudes_images() {
+        let mut img = image::RgbaImage::from_pixel(2, 2, image::Rgba([0, 0, 255, 255]));
+        img.put_pixel(0, 0, image::Rgba([255, 0, 0, 255]));
+        let mut builder = AtlasBuilder::new(16, 2, 1, TextureOptions::default());
+        builder.add_image("a", img, CENTER);
+        builder.add_image("b", solid(2, 2, [0, 255, 0, 255]), CENTER);
+        let atlas = builder.build().unwrap();
+
+        assert_eq!(atlas.pages.len(), 1);
+        let page = &atlas.pages[0];
+
+        // Padding, then one pixel of extrusion, then the image
+        assert_eq!(atlas.regions[0].frame.rect, [3.0 / 16.0, 3.0 / 16.0, 5.0 / 16.0, 5.0 / 16.0]);
+        assert_eq!(page.get_pixel(1, 1).0, [0, 0, 0, 0]);
+        assert_eq!(page.get_pixel(3, 3).0, [255, 0, 0, 255]);
+        // The corner pixel is repeated into the extrusion
+        assert_eq!(page.get_pixel(2, 2).0, [255, 0, 0, 255]);
+        assert_eq!(page.get_pixel(2, 3).0, [255, 0, 0, 255]);
+        assert_eq!(page.get_pixel(5, 5).0, [0, 0, 255, 255]);
+        assert_eq!(page.get_pixel(6, 6).0, [0, 0, 0, 0]);
+
+        // The next image starts after the extrusion and the padding in between
+        assert_eq!(atlas.regions[1].frame.rect[0], 9.0 / 16.0);
+        assert_eq!(page.get_pixel(7, 3).0, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn starts_a_new_page_after_max_sprite_frames() {
+        let mut builder = AtlasBuilder::new(1024, 1, 0, TextureOptions::default());
+        for i in 0..MAX_SPRITE_FRAMES + 1 {
+            builder.add_image(&i.to_string(), solid(1, 1, [255, 255, 255, 255]), CENTER);
+        }
+        let atlas = builder.build().unwrap();
+
+        assert_eq!(atlas.pages.len(), 2);
+        assert!(atlas.regions[..MAX_SPRITE_FRAMES].iter().all(|r| r.page == 0));
+        assert_eq!(atlas.regions[MAX_SPRITE_FRAMES].page, 1);
+        assert_eq!(atlas.regions[MAX_SPRITE_FRAMES].index, 0);
+
+        let sheets = atlas.into_sprite_sheets();
+        assert_eq!(sheets[0].frames.len(), MAX_SPRITE_FRAMES);
+        assert_eq!(sheets[1].frames.len(), 1);
+    }
+
+    #[test]
+    fn numbers_frames_in_the_order_they_were_added() {
+        let mut builder = AtlasBuilder::new(64, 1, 1, TextureOptions::default());
+        builder.add_image("short", solid(4, 2, [255, 0, 0, 255]), CENTER);
+        builder.add_image("tall", solid(4, 8, [0, 255, 0, 255]), [0.5, 1.0]);
+        let atlas = builder.build().unwrap();
+
+        // Packed tallest first but numbered as added
+        assert!(atlas.regions[1].frame.rect[0] < atlas.regions[0].frame.rect[0]);
+        assert_eq!(atlas.regions[0].index, 0);
+        assert_eq!(atlas.regions[1].index, 1);
+        assert_eq!(atlas.regions[1].frame.pivot, [0.5, 1.0]);
+    }
+
+    #[test]
+    fn adds_every_frame_of_a_sheet() {
+        let mut img = solid(4, 2, [255, 0, 0, 255]);
+        for y in 0..2 {
+            for x in 2..4 {
+                img.put_pixel(x, y, image::Rgba([0, 255, 0, 255]));
+            }
+        }
+        let sheet = SpriteSheet {
+            path: None,
+            image_path: PathBuf::from("test"),
+            image: Some(img),
+            texture_options: TextureOptions::default(),
+            frames: vec![
+                Frame { rect: [0.0, 0.0, 0.5, 1.0], pivot: CENTER },
+                Frame { rect: [0.5, 0.0, 1.0, 1.0], pivot: [0.0, 1.0] },
+            ],
+            animations: Vec::new(),
+        };
+        let mut builder = AtlasBuilder::new(16, 0, 0, TextureOptions::default());
+        builder.add_sprite_sheet("sheet", &sheet).unwrap();
+        let atlas = builder.build().unwrap();
+
+        assert_eq!(atlas.regions.len(), 2);
+        assert_eq!(atlas.regions[1].frame.pivot, [0.0, 1.0]);
+        assert_eq!(atlas.pages[0].get_pixel(0, 0).0, [255, 0, 0, 255]);
+        assert_eq!(atlas.pages[0].get_pixel(2, 0).0, [0, 255, 0, 255]);
+    }
+
+    #[test]
+    fn rejects_images_larger_than_a_page() {
+        let mut builder = AtlasBuilder::new(16, 2, 1, TextureOptions::default());
+        builder.add_image("big", solid(16, 4, [255, 255, 255, 255]), CENTER);
+        assert!(builder.build().is_err());
+    }
 }
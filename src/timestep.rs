@@ -0,0 +1,179 @@
+use std::time::Duration;
+
+use crate::clock::Clock;
+
+/// Fixed-step simulation clock. Wall-clock time is collected into an
+/// accumulator which is then consumed in `tick_duration` sized steps, so the
+/// simulation advances the same way regardless of frame pacing.
+pub struct FixedTimestep {
+    pub tick_duration: Duration,
+    pub max_steps: u32,
+    pub frame_time: Duration,
+    accumulator: Duration,
+    last_time: Duration,
+}
+
+impl FixedTimestep {
+    pub fn new(tick_rate: u32, max_steps: u32, clock: &dyn Clock) -> FixedTimestep {
+        FixedTimestep {
+            tick_duration: Duration::from_secs(1) / tick_rate,
+            max_steps,
+            frame_time: Duration::from_secs(0),
+            accumulator: Duration::from_secs(0),
+            last_time: clock.now(),
+        }
+    }
+
+    // Measures the time passed on the clock since the previous call and returns
+    // how many simulation ticks should be run this frame
+    pub fn advance(&mut self, clock: &dyn Clock) -> u32 {
+        let now = clock.now();
+        self.frame_time = now - self.last_time;
+        self.last_time = now;
+
+        self.accumulate(self.frame_time)
+    }
+
+    pub fn accumulate(&mut self, elapsed: Duration) -> u32 {
+        self.accumulator += elapsed;
+
+        let mut steps = 0;
+        while self.accumulator >= self.tick_duration && steps < self.max_steps {
+            self.accumulator -= self.tick_duration;
+            steps += 1;
+        }
+
+        // If we can't keep up, drop the backlog instead of spiralling further
+        // behind on every frame. Only the partial tick is kept for interpolation.
+        if self.accumulator >= self.tick_duration {
+            let remainder = self.accumulator.as_nanos() % self.tick_duration.as_nanos();
+            self.accumulator = Duration::from_nanos(remainder as u64);
+        }
+
+        steps
+    }
+
+    // How far we are between the previous and the next tick, in range [0, 1)
+    pub fn alpha(&self) -> f32 {
+        self.accumulator.as_secs_f32() / self.tick_duration.as_secs_f32()
+    }
+}
+
+/// Paces rendering at a fixed frame rate, independent of the tick rate.
+/// Frames in between ticks are interpolated with `FixedTimestep::alpha`.
+pub struct FramePacer {
+    pub frame_duration: Duration,
+    next_frame: Duration,
+}
+
+impl FramePacer {
+    pub fn new(frame_rate: u32, clock: &dyn Clock) -> FramePacer {
+        FramePacer {
+            frame_duration: Duration::from_secs(1) / frame_rate,
+            next_frame: clock.now(),
+        }
+    }
+
+    // Whether the next frame is due and if so moves the deadline one frame
+    // on. After a stall the deadline restarts from now instead of catching up
+    // with a burst of frames.
+    pub fn frame_due(&mut self, clock: &dyn Clock) -> bool {
+        let now = clock.now();
+        if now < self.next_frame {
+            return false;
+        }
+        self.next_frame += self.frame_duration;
+        if self.next_frame <= now {
+            self.next_frame = now + self.frame_duration;
+        }
+        true
+    }
+
+    // Wall-clock time left before the next frame is due
+    pub fn until_next_frame(&self, clock: &dyn Clock) -> Duration {
+        self.next_frame.checked_sub(clock.now()).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn millis(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn accumulates_whole_ticks_and_keeps_the_rest() {
+        let clock = ManualClock::new();
+        let mut timestep = FixedTimestep::new(100, 5, &clock);
+
+        assert_eq!(timestep.accumulate(millis(4)), 0);
+        assert_eq!(timestep.accumulate(millis(4)), 0);
+        assert_eq!(timestep.accumulate(millis(4)), 1);
+        assert_eq!(timestep.accumulate(millis(28)), 3);
+        assert!((timestep.alpha() - 0.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn drops_the_backlog_past_max_steps() {
+        let clock = ManualClock::new();
+        let mut timestep = FixedTimestep::new(100, 5, &clock);
+
+        // A one second stall runs five ticks, the rest is dropped except the
+        // partial tick
+        assert_eq!(timestep.accumulate(millis(1003)), 5);
+        assert!((timestep.alpha() - 0.3).abs() < 1e-4);
+        assert_eq!(timestep.accumulate(millis(7)), 1);
+    }
+
+    #[test]
+    fn alpha_is_the_fraction_of_the_next_tick() {
+        let clock = ManualClock::new();
+        let mut timestep = FixedTimestep::new(100, 5, &clock);
+
+        timestep.accumulate(millis(2));
+        assert!((timestep.alpha() - 0.2).abs() < 1e-4);
+        timestep.accumulate(millis(5));
+        assert!((timestep.alpha() - 0.7).abs() < 1e-4);
+        timestep.accumulate(millis(3));
+        assert!(timestep.alpha().abs() < 1e-4);
+    }
+
+    #[test]
+    fn advance_measures_the_clock() {
+        let mut clock = ManualClock::new();
+        let mut timestep = FixedTimestep::new(100, 5, &clock);
+
+        clock.advance(millis(25));
+        assert_eq!(timestep.advance(&clock), 2);
+        assert_eq!(timestep.frame_time, millis(25));
+        assert!((timestep.alpha() - 0.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn paces_frames_and_restarts_after_a_stall() {
+        let mut clock = ManualClock::new();
+        let mut frames = FramePacer::new(50, &clock);
+
+        assert!(frames.frame_due(&clock));
+        assert!(!frames.frame_due(&clock));
+        assert_eq!(frames.until_next_frame(&clock), millis(20));
+
+        clock.advance(millis(15));
+        assert!(!frames.frame_due(&clock));
+        assert_eq!(frames.until_next_frame(&clock), millis(5));
+
+        // A late frame keeps the cadence
+        clock.advance(millis(7));
+        assert!(frames.frame_due(&clock));
+        assert_eq!(frames.until_next_frame(&clock), millis(18));
+
+        // A stall doesn't queue up the missed frames
+        clock.advance(millis(100));
+        assert!(frames.frame_due(&clock));
+        assert!(!frames.frame_due(&clock));
+        assert_eq!(frames.until_next_frame(&clock), millis(20));
+    }
+}